@@ -0,0 +1,51 @@
+//! A dependency-free benchmark of the `use_state` hot path.
+//!
+//! Run with `cargo bench --bench use_state`, optionally with `--features fast-hash` to compare
+//! hashers.
+
+use std::time::{Duration, Instant};
+
+const CALL_SITES: u32 = 1_000;
+const PASSES: u32 = 200;
+
+fn pass() {
+    hooks::root(|| {
+        for i in 0..CALL_SITES {
+            hooks::call_in_slot(&i, || hooks::use_state(|| i));
+        }
+    });
+}
+
+fn bench(name: &str, mut op: impl FnMut()) {
+    // warm up so that every call site already holds state
+    op();
+
+    let start = Instant::now();
+    for _ in 0..PASSES {
+        op();
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "{:<24} {:>10.1} ns/call",
+        name,
+        per_call(elapsed, PASSES * CALL_SITES)
+    );
+}
+
+fn per_call(elapsed: Duration, calls: u32) -> f64 {
+    elapsed.as_nanos() as f64 / f64::from(calls)
+}
+
+fn main() {
+    bench("use_state", pass);
+    bench("use_state + sweep", || {
+        pass();
+        hooks::sweep();
+    });
+    // every call site allocates its key and inserts fresh state
+    bench("use_state (insert)", || {
+        hooks::clear_all();
+        pass();
+    });
+}
@@ -0,0 +1,53 @@
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields};
+
+/// Derives a `use_state` constructor for a struct whose fields are all `State`s, which creates
+/// or provides each field's state with its `Default` value.
+///
+/// Each field's state is rooted at the field's name within the call site of the constructor, so
+/// reordering the fields doesn't mix up their state.
+#[proc_macro_derive(Hooks)]
+pub fn derive_hooks(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return error(
+                    name,
+                    "`Hooks` can only be derived for structs with named fields",
+                )
+            }
+        },
+        _ => return error(name, "`Hooks` can only be derived for structs"),
+    };
+
+    let inits = fields.iter().map(|field| {
+        let field = &field.ident;
+        quote! {
+            #field: ::hooks::call_in_slot(stringify!(#field), ::hooks::use_state_default)
+        }
+    });
+
+    let expanded = quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            /// Creates the state of each field, or provides handles to it if it already exists.
+            #[track_caller]
+            pub fn use_state() -> Self {
+                ::hooks::call_in_slot(stringify!(#name), || Self { #(#inits,)* })
+            }
+        }
+    };
+
+    expanded.into()
+}
+
+fn error(name: &syn::Ident, message: &str) -> TokenStream {
+    syn::Error::new(name.span(), message)
+        .to_compile_error()
+        .into()
+}
@@ -1,295 +1,4188 @@
+use std::any::TypeId;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
+use std::ops::{Add, Sub};
 use std::rc::Rc;
 
 use anymap::any::Any;
-use slotmap::{DefaultKey, DenseSlotMap, Key, SecondaryMap};
+use slotmap::{DefaultKey, DenseSlotMap, SecondaryMap};
 
-pub use topo::{call_in_slot, nested, root};
+pub use topo::{call_in_slot, nested};
+
+#[cfg(feature = "derive")]
+pub use hooks_derive::Hooks;
+
+// lets the code generated by `hooks_derive` refer to this crate by name from within it
+#[cfg(feature = "derive")]
+extern crate self as hooks;
+
+/// The hooks and types most code using this crate needs, for glob importing.
+pub mod prelude {
+    pub use crate::{
+        batch, call_in_slot, nested, root, sweep, use_callback, use_computed, use_counter,
+        use_counter_of, use_effect, use_id, use_memo, use_mount, use_previous, use_reducer,
+        use_reducer_with_middleware, use_ref, use_shared_state, use_state, use_state_default,
+        use_state_init_once, use_state_keyed, use_state_tuple, use_state_when, use_state_with_deps,
+        use_toggle, use_unmount, HookError, State,
+    };
+}
+
+// call site ids are hashed on every hook call, so a faster hasher can be opted into
+#[cfg(feature = "fast-hash")]
+type IdHasher = ahash::RandomState;
+#[cfg(not(feature = "fast-hash"))]
+type IdHasher = std::collections::hash_map::RandomState;
 
 thread_local! {
-    static STORE: RefCell<Store> = RefCell::new(Store::new());
+    static STORE: Rc<RefCell<Store>> = Rc::new(RefCell::new(Store::new()));
+    // the call sites of the `root` calls currently running, innermost last
+    static ROOTS: RefCell<Vec<&'static std::panic::Location<'static>>> = const { RefCell::new(Vec::new()) };
 }
 
-/// Clears any state which was not accessed since the last sweep.
-pub fn sweep() {
-    STORE.with(|store_refcell| {
-        store_refcell.borrow_mut().sweep();
-    });
+fn default_store() -> Rc<RefCell<Store>> {
+    STORE.with(Rc::clone)
 }
 
-/// Creates new local state with the given `data_fn`, or provides a handle to the local state
-/// if it already exists.
-pub fn use_state<T: 'static, F: FnOnce() -> T>(data_fn: F) -> State<T> {
-    let id = Id::new();
+/// Returns the topo call id of the current scope, which is what the ids of state created in it
+/// are derived from.
+///
+/// ```
+/// let id = || hooks::root(|| hooks::call_in_slot("item", hooks::current_call_id));
+/// assert_eq!(id(), id());
+/// ```
+pub fn current_call_id() -> topo::CallId {
+    topo::CallId::current()
+}
 
-    if !state_exists_for_id::<Rc<RefCell<T>>>(id) {
-        set_state_with_id::<Rc<RefCell<T>>>(Rc::new(RefCell::new(data_fn())), id);
-    } else if !state_marked_with_id::<Rc<RefCell<T>>>(id) {
-        mark_state_with_id::<Rc<RefCell<T>>>(id);
-    }
+/// Runs `op` at the root of a new call graph, as `topo::root` does.
+///
+/// Every root gives the calls made directly in it the same ids, so state is shared between roots
+/// like any other. Only `use_shared_state` tells roots apart, by the call site of `root`.
+#[track_caller]
+pub fn root<F: FnOnce() -> R, R>(op: F) -> R {
+    let location = std::panic::Location::caller();
+    ROOTS.with(|roots| roots.borrow_mut().push(location));
 
-    State::new(id)
+    let _root = Root;
+    topo::root(op)
 }
 
-pub struct State<T> {
-    data: Rc<RefCell<T>>,
+// the call site of the innermost `root` currently running
+fn current_root() -> Option<&'static std::panic::Location<'static>> {
+    ROOTS.with(|roots| roots.borrow().last().copied())
 }
 
-impl<T> std::fmt::Debug for State<T>
-where
-    T: std::fmt::Debug,
-{
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "({:#?})", self.data)
+// ends a `root` when dropped, even if its closure panicked
+struct Root;
+
+impl Drop for Root {
+    fn drop(&mut self) {
+        ROOTS.with(|roots| roots.borrow_mut().pop());
     }
 }
 
-impl<T> Clone for State<T> {
-    fn clone(&self) -> State<T> {
-        State::<T> {
-            data: self.data.clone(),
-        }
-    }
+/// A store of local state which is independent of the thread's default store.
+///
+/// The free functions (`use_state`, `sweep`, etc.) all operate on the default store, while the
+/// methods here only ever touch state held by this one, so that e.g. separate subsystems may be
+/// swept on their own schedules.
+#[derive(Clone)]
+pub struct StoreHandle {
+    store: Rc<RefCell<Store>>,
 }
 
-impl<T> State<T>
-where
-    T: 'static,
-{
-    fn new(id: Id) -> Self {
-        Self {
-            data: read_state_with_id::<Rc<RefCell<T>>, _, Rc<RefCell<T>>>(id, |x| x.clone()),
-        }
+impl StoreHandle {
+    pub fn new() -> Self {
+        Self::with_policy(ClearUnaccessed)
     }
 
-    pub fn controlled(data: T) -> Self {
+    /// Creates a store whose `sweep` clears state as decided by `policy`.
+    pub fn with_policy<P: SweepPolicy + 'static>(policy: P) -> Self {
         Self {
-            data: Rc::new(RefCell::new(data))
+            store: Rc::new(RefCell::new(Store::with_policy(Box::new(policy)))),
         }
     }
 
-    pub fn set<F: FnOnce(&mut T) -> U, U>(&self, func: F) -> U {
-        func(&mut self.data.borrow_mut())
+    /// Like `use_state`, but for state held by this store.
+    #[track_caller]
+    pub fn use_state<T: 'static, F: FnOnce() -> T>(&self, data_fn: F) -> State<T> {
+        use_state_in(&self.store, data_fn, std::panic::Location::caller())
     }
 
-    pub fn get<F: FnOnce(&T) -> U, U>(&self, func: F) -> U {
-        func(&self.data.borrow())
+    /// Like `sweep`, but only clears state held by this store.
+    pub fn sweep(&self) {
+        sweep_store(&self.store);
+    }
+}
+
+impl Default for StoreHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decides which state is cleared by a sweep, for stores created with `StoreHandle::with_policy`.
+pub trait SweepPolicy {
+    /// Returns whether the state at `id`, which was not accessed since the previous sweep, should
+    /// be cleared. State which is kept is offered to the policy again on the next sweep, unless
+    /// it's accessed in the meantime.
+    ///
+    /// This is called while the store is borrowed, so it must not use hooks from the same store.
+    fn should_clear(&mut self, id: Id) -> bool;
+}
+
+/// The default `SweepPolicy`, which clears all state not accessed since the previous sweep.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClearUnaccessed;
+
+impl SweepPolicy for ClearUnaccessed {
+    fn should_clear(&mut self, _id: Id) -> bool {
+        true
     }
 }
 
-fn set_state_with_id<T: 'static>(data: T, current_id: Id) {
+/// Allows state of type `T` to be copied by `fork_store`.
+pub fn register_clone<T: Clone + 'static>() {
+    STORE.with(|store_refcell| store_refcell.borrow_mut().register_clone::<T>());
+}
+
+/// Returns an independent copy of the default store, e.g. as a snapshot for tests.
+///
+/// Only state of types registered with `register_clone` is copied, by cloning each value.
+/// Subscribers, cleanups, metadata and the sweep policy aren't copied.
+pub fn fork_store() -> StoreHandle {
+    let fork = STORE.with(|store_refcell| store_refcell.borrow().fork());
+
+    StoreHandle {
+        store: Rc::new(RefCell::new(fork)),
+    }
+}
+
+/// Clears any state which was not accessed since the last sweep.
+///
+/// State is accessed by the hook which provides it, and by writes through any of its handles.
+/// Reads through a handle don't count.
+pub fn sweep() {
+    sweep_with_report();
+}
+
+/// Runs `body` in a new `root` and then sweeps, simulating a single frame of an application.
+///
+/// Meant for testing hooks, where it saves managing roots and sweeps by hand.
+pub fn test_cycle<R, F: FnOnce() -> R>(body: F) -> R {
+    let result = root(body);
+    sweep();
+    result
+}
+
+/// Runs `test_cycle` with `body` `n` times, returning the result of the last run.
+///
+/// # Panics
+///
+/// If `n` is zero.
+pub fn test_cycles<R, F: FnMut() -> R>(n: usize, mut body: F) -> R {
+    assert!(n > 0, "`test_cycles` needs to run at least once");
+    (1..n).for_each(|_| {
+        test_cycle(&mut body);
+    });
+    test_cycle(body)
+}
+
+/// Like `sweep`, but returns a summary of the state it cleared.
+pub fn sweep_with_report() -> SweepReport {
+    sweep_store(&default_store())
+}
+
+/// Clears all state which was last accessed more than `cycles` sweeps ago, without starting a
+/// new sweep cycle.
+///
+/// Without a custom `SweepPolicy` only state accessed during the previous cycle is old enough to
+/// be cleared, by `sweep_older_than(0)`.
+pub fn sweep_older_than(cycles: u64) -> SweepReport {
+    let swept = STORE.with(|store_refcell| store_refcell.borrow_mut().sweep_older_than(cycles));
+    swept.finish()
+}
+
+fn sweep_store(store: &RefCell<Store>) -> SweepReport {
+    let swept = store.borrow_mut().sweep();
+    swept.finish()
+}
+
+/// A summary of the state cleared by `sweep_with_report`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SweepReport {
+    /// The number of call sites whose state was cleared.
+    pub ids_cleared: usize,
+    /// The number of state types with storage which was cleared.
+    pub maps_cleared: usize,
+}
+
+/// Clears all state, including state which is exempt from sweeping.
+pub fn clear_all() {
+    let cleared = STORE.with(|store_refcell| store_refcell.borrow_mut().clear_all());
+    cleared.finish();
+}
+
+/// Reserves room for at least `additional` more call sites in the store.
+///
+/// This is only a performance hint for apps which create many hooks at once.
+pub fn reserve(additional: usize) {
     STORE.with(|store_refcell| {
-        store_refcell
-            .borrow_mut()
-            .set_state_with_id::<T>(data, &current_id)
+        store_refcell.borrow_mut().reserve(additional);
     });
 }
 
-fn mark_state_with_id<T: 'static>(current_id: Id) {
+/// Releases memory the store no longer needs, e.g. after a large amount of state was swept.
+///
+/// The storage of each state type is reallocated to fit the state it holds, or dropped entirely
+/// if it holds none, and so are the maps of call site keys, subscribers, cleanups and metadata.
+/// The slot map of call site entries keeps its capacity, since it can't be shrunk.
+pub fn shrink_to_fit() {
+    STORE.with(|store_refcell| store_refcell.borrow_mut().shrink_to_fit());
+}
+
+/// Reserves room for at least `additional` more states of type `T` in both sweep buffers.
+///
+/// Like `reserve`, this is only a performance hint: a buffer's storage is released whenever it
+/// is cleared by `sweep`.
+pub fn reserve_for<T: 'static>(additional: usize) {
     STORE.with(|store_refcell| {
         store_refcell
             .borrow_mut()
-            .mark_state_with_id::<T>(&current_id)
+            .reserve_for::<Rc<RefCell<T>>>(additional);
     });
 }
 
-fn state_exists_for_id<T: 'static>(id: Id) -> bool {
-    STORE.with(|store_refcell| store_refcell.borrow().state_exists_with_id::<T>(id))
-}
+/// Calls `func` with every state of type `T` which was accessed since the last sweep, along with
+/// all state of type `T` which is exempt from sweeping.
+///
+/// States are visited in the order their call sites were first given state, so the order is
+/// the same across runs which call the same hooks.
+pub fn for_each_state<T: 'static, F: FnMut(Id, &T)>(mut func: F) {
+    // collected first so that `func` may use the store
+    let states = STORE.with(|store_refcell| store_refcell.borrow().live_states::<Rc<RefCell<T>>>());
 
-fn state_marked_with_id<T: 'static>(id: Id) -> bool {
-    STORE.with(|store_refcell| store_refcell.borrow().state_marked_with_id::<T>(id))
+    for (id, data) in states {
+        func(id, &data.borrow());
+    }
 }
 
-fn remove_state_with_id<T: 'static>(id: Id) -> Option<T> {
-    STORE.with(|store_refcell| store_refcell.borrow_mut().remove_state_with_id::<T>(&id))
+/// Returns a copy of every state of type `T` visited by `for_each_state`, in the same order.
+pub fn snapshot<T: Clone + 'static>() -> Vec<(Id, T)> {
+    let mut states = Vec::new();
+    for_each_state(|id, data: &T| states.push((id, data.clone())));
+    states
 }
 
-fn read_state_with_id<T: 'static, F: FnOnce(&T) -> R, R>(id: Id, func: F) -> R {
-    let item = remove_state_with_id::<T>(id).expect("State does not exist.");
-    let read = func(&item);
-    set_state_with_id(item, id);
-    read
+/// Returns the label given to the call site `id` with `State::label`, e.g. to name the states
+/// visited by `for_each_state`.
+pub fn label_for(id: Id) -> Option<&'static str> {
+    STORE.with(|store_refcell| store_refcell.borrow().labels.get(&id).copied())
 }
 
-#[derive(Clone, Copy, Eq, PartialEq, Debug, Hash)]
-struct Id {
-    id: topo::CallId,
+/// Returns the number of states of type `T` held by the store.
+///
+/// The count includes state in both the active and the reverse sweep buffers (i.e. state which
+/// will be cleared by the next sweep unless accessed), as well as state exempt from sweeping.
+pub fn count_states<T: 'static>() -> usize {
+    STORE.with(|store_refcell| store_refcell.borrow().count_states::<Rc<RefCell<T>>>())
 }
 
-impl Id {
-    #[topo::nested]
-    fn new() -> Self {
-        Self {
-            id: topo::CallId::current(),
+/// Marks the state of type `T` at each of `ids` as accessed, protecting it from the next sweep
+/// without reading it.
+pub fn mark_all<T: 'static>(ids: &[Id]) {
+    STORE.with(|store_refcell| {
+        let mut store = store_refcell.borrow_mut();
+
+        for id in ids {
+            store.mark_state_with_id::<Rc<RefCell<T>>>(id);
         }
-    }
+    });
 }
 
-#[derive(Clone, Copy)]
-enum Mode {
+/// Returns the ids of all call sites whose state was accessed since the last sweep.
+///
+/// Call sites which only hold state exempt from sweeping are not included.
+pub fn marked_ids() -> Vec<Id> {
+    STORE.with(|store_refcell| store_refcell.borrow().marked_ids())
+}
+
+/// Returns a human-readable summary of the store's contents, for logging during development.
+pub fn debug_dump() -> String {
+    STORE.with(|store_refcell| store_refcell.borrow().debug_dump())
+}
+
+/// Returns statistics about the state currently held by the store.
+pub fn store_stats() -> StoreStats {
+    STORE.with(|store_refcell| store_refcell.borrow().stats())
+}
+
+/// Returns the names of the types the store has held, in the order they were first stored.
+///
+/// These are the types as stored, so local state of type `T` is listed as `Rc<RefCell<T>>`.
+pub fn registered_types() -> Vec<&'static str> {
+    STORE.with(|store_refcell| store_refcell.borrow().registered_types())
+}
+
+/// Returns the buffer which currently receives accessed state. The other buffer holds state which
+/// the next sweep will clear unless it's accessed first.
+pub fn current_phase() -> SweepPhase {
+    STORE.with(|store_refcell| store_refcell.borrow().mode.into())
+}
+
+/// A summary of the state held by the store, as returned by `store_stats`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StoreStats {
+    /// The number of call sites which hold state.
+    pub tracked_ids: usize,
+    /// The number of state types with storage in buffer A.
+    pub maps_a: usize,
+    /// The number of state types with storage in buffer B.
+    pub maps_b: usize,
+    /// The number of state types with storage exempt from sweeping.
+    pub maps_persistent: usize,
+    /// The buffer which currently receives accessed state.
+    pub phase: SweepPhase,
+}
+
+/// One of the two buffers the store alternates between on each `sweep`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SweepPhase {
     A,
     B,
 }
 
-impl Mode {
-    fn reverse(&self) -> Self {
-        match self {
-            Self::A => Self::B,
-            Self::B => Self::A,
+impl From<Mode> for SweepPhase {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::A => Self::A,
+            Mode::B => Self::B,
         }
     }
 }
 
-struct Store {
-    data_a: anymap::Map<dyn Any>,
-    data_b: anymap::Map<dyn Any>,
-    mode: Mode,
-    keys_by_id: HashMap<Id, DefaultKey>,
-    ids: DenseSlotMap<DefaultKey, Id>,
+/// Creates new local state with the given `data_fn`, or provides a handle to the local state
+/// if it already exists.
+#[track_caller]
+pub fn use_state<T: 'static, F: FnOnce() -> T>(data_fn: F) -> State<T> {
+    use_state_in(&default_store(), data_fn, std::panic::Location::caller())
 }
 
-impl Store {
-    pub fn new() -> Store {
-        Store {
-            data_a: anymap::Map::new(),
-            data_b: anymap::Map::new(),
-            ids: DenseSlotMap::new(),
-            keys_by_id: HashMap::new(),
-            mode: Mode::A,
-        }
-    }
+/// Returns the id of this call site without storing any state for it, e.g. to key external
+/// resources. Repeated calls at the same call site return the same id.
+///
+/// The id is registered with the store, but since it holds no state it's dropped again by the
+/// next sweep.
+pub fn use_id() -> Id {
+    let id = Id::new();
+    STORE.with(|store_refcell| store_refcell.borrow_mut().key_for_or_insert(&id));
+    id
+}
 
-    pub fn sweep(&mut self) {
-        match self.mode {
-            Mode::A => {
-                self.data_b.clear();
-                self.mode = Mode::B;
-            }
-            Mode::B => {
-                self.data_a.clear();
-                self.mode = Mode::A;
-            }
-        }
-    }
+/// Like `use_state`, but also returns a setter which replaces the state with the value it's given.
+#[track_caller]
+pub fn use_state_tuple<T: 'static, F: FnOnce() -> T>(init: F) -> (State<T>, impl Fn(T)) {
+    let state = use_state(init);
+    let setter_state = state.clone();
 
-    pub fn state_exists_with_id<T: 'static>(&self, id: Id) -> bool {
-        self.state_exists::<T>(self.mode, id) || self.state_exists::<T>(self.mode.reverse(), id)
-    }
+    (state, move |value| setter_state.set_value(value))
+}
 
-    pub fn state_marked_with_id<T: 'static>(&self, id: Id) -> bool {
-        self.state_exists::<T>(self.mode, id) || !self.state_exists::<T>(self.mode.reverse(), id)
-    }
+/// Creates local state with `T::default()`, or provides a handle to the local state if it already
+/// exists.
+#[track_caller]
+pub fn use_state_default<T: Default + 'static>() -> State<T> {
+    use_state(T::default)
+}
 
-    pub fn remove_state_with_id<T: 'static>(&mut self, current_id: &Id) -> Option<T> {
-        //unwrap or default to keep borrow checker happy
-        let key = self.keys_by_id.get(current_id).copied().unwrap_or_default();
+/// Creates a counter starting out at `initial`, or provides a handle to it if it already exists.
+#[track_caller]
+pub fn use_counter(initial: i64) -> Counter {
+    use_counter_of(initial)
+}
 
-        if key.is_null() {
-            None
-        } else {
-            self.get_mut_secondarymap::<T>(self.mode).remove(key)
-        }
+/// Like `use_counter`, but for a counter of any type with arithmetic, e.g. `usize` or `f64`.
+#[track_caller]
+pub fn use_counter_of<T>(initial: T) -> Counter<T>
+where
+    T: Add<Output = T> + Sub<Output = T> + Copy + 'static,
+{
+    Counter {
+        state: use_state(|| (initial, initial)),
     }
+}
 
-    pub fn set_state_with_id<T: 'static>(&mut self, data: T, current_id: &Id) {
-        let key = self.keys_by_id.get(current_id).copied().unwrap_or_default();
+/// A handle to a counter, as returned by `use_counter` and `use_counter_of`.
+#[derive(Clone, Debug)]
+pub struct Counter<T = i64> {
+    // the current value, and the one the counter was created with
+    state: State<(T, T)>,
+}
 
-        if key.is_null() {
-            let key = self.ids.insert(*current_id);
-            self.keys_by_id.insert(*current_id, key);
-            self.get_mut_secondarymap::<T>(self.mode).insert(key, data);
-        } else {
-            self.get_mut_secondarymap::<T>(self.mode).insert(key, data);
-        }
+impl<T> Counter<T>
+where
+    T: Add<Output = T> + Sub<Output = T> + Copy + 'static,
+{
+    /// Returns the current value.
+    pub fn value(&self) -> T {
+        self.state.get(|(value, _)| *value)
     }
 
-    pub fn mark_state_with_id<T: 'static>(&mut self, current_id: &Id) {
-        let key = self.keys_by_id.get(current_id).copied().unwrap_or_default();
+    /// Adds `n` to the value.
+    pub fn add(&self, n: T) {
+        self.state.set(|(value, _)| *value = *value + n);
+    }
 
-        if !key.is_null() {
-            let data = self
-                .get_mut_secondarymap::<T>(self.mode.reverse())
-                .remove(key)
-                .unwrap();
-            self.get_mut_secondarymap(self.mode).insert(key, data);
-        }
+    /// Subtracts `n` from the value.
+    pub fn sub(&self, n: T) {
+        self.state.set(|(value, _)| *value = *value - n);
     }
 
-    fn state_exists<T: 'static>(&self, mode: Mode, id: Id) -> bool {
-        match (self.keys_by_id.get(&id), self.get_secondarymap::<T>(mode)) {
-            (Some(existing_key), Some(existing_secondary_map)) => {
-                existing_secondary_map.contains_key(*existing_key)
-            }
-            (_, _) => false,
-        }
+    /// Returns the value to the one the counter was created with.
+    pub fn reset(&self) {
+        self.state.set(|(value, initial)| *value = *initial);
     }
+}
 
-    fn get_secondarymap<T: 'static>(&self, mode: Mode) -> Option<&SecondaryMap<DefaultKey, T>> {
-        self.get_datamap(mode).get::<SecondaryMap<DefaultKey, T>>()
+impl<T> Counter<T>
+where
+    T: Add<Output = T> + Sub<Output = T> + Copy + From<u8> + 'static,
+{
+    /// Adds one to the value.
+    pub fn increment(&self) {
+        self.add(T::from(1));
     }
 
-    fn get_mut_secondarymap<T: 'static>(&mut self, mode: Mode) -> &mut SecondaryMap<DefaultKey, T> {
-        if self
-            .get_datamap_mut(mode)
-            .get_mut::<SecondaryMap<DefaultKey, T>>()
-            .is_some()
-        {
-            self.get_datamap_mut(mode)
-                .get_mut::<SecondaryMap<DefaultKey, T>>()
-                .unwrap()
-        } else {
-            self.register_secondarymap::<T>(mode);
-            self.get_datamap_mut(mode)
-                .get_mut::<SecondaryMap<DefaultKey, T>>()
-                .unwrap()
-        }
+    /// Subtracts one from the value.
+    pub fn decrement(&self) {
+        self.sub(T::from(1));
     }
+}
 
-    fn register_secondarymap<T: 'static>(&mut self, mode: Mode) {
-        let sm: SecondaryMap<DefaultKey, T> = SecondaryMap::new();
-        self.get_datamap_mut(mode).insert(sm);
+/// Creates boolean local state starting out as `initial`, along with a function which flips it.
+#[track_caller]
+pub fn use_toggle(initial: bool) -> (State<bool>, impl Fn()) {
+    let state = use_state(|| initial);
+    let toggle_state = state.clone();
+
+    (state, move || toggle_state.set(|on| *on = !*on))
+}
+
+/// Like `use_state`, but roots the state at `key` rather than at the position of the call, so
+/// that e.g. each item of a loop keeps its own state even when the items are reordered.
+///
+/// This is equivalent to wrapping the call to `use_state` in `call_in_slot(&key, ..)`.
+pub fn use_state_keyed<K, T, F>(key: K, init: F) -> State<T>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+    T: 'static,
+    F: FnOnce() -> T,
+{
+    call_in_slot(&key, || use_state(init))
+}
+
+/// Like `use_state_keyed`, but roots the state at `key` alone, so that every call with an equal
+/// key under the same root gets a handle to the same state wherever it's made.
+///
+/// Neither the call site nor the enclosing scopes play a part in the state's identity: state is
+/// shared by every call under the same root whose key is of the same type and compares equal, and
+/// whose state is of the same type `T`. Roots are told apart by the call site of `root`, so the
+/// calls made each frame under an application's root share state, but not with calls under a root
+/// called elsewhere. Calls made outside of any `root` share state with each other. Calls with an equal key but a different `T` get separate state,
+/// and shared state is never mixed up with state from other hooks, e.g. `use_state_keyed` with the
+/// same key. `init` is only called by the first of the calls to run after the state was last
+/// cleared, and the state stays alive as long as any of them is made between sweeps.
+pub fn use_shared_state<K, T, F>(key: K, init: F) -> State<T>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+    T: 'static,
+    F: FnOnce() -> T,
+{
+    let key = SharedKey {
+        key,
+        type_id: TypeId::of::<T>(),
+        root: current_root(),
+    };
+
+    topo::root(|| call_in_slot(&key, || use_state(init)))
+}
+
+// the slot of `use_shared_state`, which is private so that no other slot can equal it
+#[derive(Clone, Eq, Hash, PartialEq)]
+struct SharedKey<K> {
+    key: K,
+    type_id: TypeId,
+    root: Option<&'static std::panic::Location<'static>>,
+}
+
+fn use_state_in<T: 'static, F: FnOnce() -> T>(
+    store: &Rc<RefCell<Store>>,
+    data_fn: F,
+    location: &'static std::panic::Location<'static>,
+) -> State<T> {
+    use_state_with_id(store, Id::new(), data_fn, location)
+}
+
+fn use_state_with_id<T: 'static, F: FnOnce() -> T>(
+    store: &Rc<RefCell<Store>>,
+    id: Id,
+    data_fn: F,
+    location: &'static std::panic::Location<'static>,
+) -> State<T> {
+    check_hook_call::<T>(store, id, location);
+
+    if !state_exists_for_id::<Rc<RefCell<T>>>(store, id) {
+        set_state_with_id::<Rc<RefCell<T>>>(store, Rc::new(RefCell::new(data_fn())), id);
+    } else if !state_marked_with_id::<Rc<RefCell<T>>>(store, id) {
+        mark_state_with_id::<Rc<RefCell<T>>>(store, id);
     }
 
-    fn get_datamap(&self, mode: Mode) -> &anymap::Map<dyn Any> {
-        match mode {
-            Mode::A => &self.data_a,
-            Mode::B => &self.data_b,
-        }
+    State::new(store, id)
+}
+
+/// Like `use_state`, but only provides the state while `cond` is true, returning `None` otherwise.
+///
+/// The call site keeps its identity whatever `cond` is, so it's safe to call this conditionally
+/// where a plain `use_state` would have to be skipped. While `cond` is false the state isn't
+/// accessed, so it's cleared as usual by the second sweep after `cond` became false. If `cond`
+/// becomes true again before then the existing state is returned, otherwise it's initialized anew
+/// with `init`.
+#[track_caller]
+pub fn use_state_when<T: 'static, F: FnOnce() -> T>(cond: bool, init: F) -> Option<State<T>> {
+    use_state_when_in(&default_store(), cond, init, std::panic::Location::caller())
+}
+
+fn use_state_when_in<T: 'static, F: FnOnce() -> T>(
+    store: &Rc<RefCell<Store>>,
+    cond: bool,
+    init: F,
+    location: &'static std::panic::Location<'static>,
+) -> Option<State<T>> {
+    // the id is created either way, so that `cond` doesn't shift the ids of later calls
+    let id = Id::new();
+    if cond {
+        Some(use_state_with_id(store, id, init, location))
+    } else {
+        None
     }
+}
 
-    fn get_datamap_mut(&mut self, mode: Mode) -> &mut anymap::Map<dyn Any> {
-        match mode {
-            Mode::A => &mut self.data_a,
-            Mode::B => &mut self.data_b,
-        }
+/// Returns the local state at this call site if it exists, or an entry which may be used to
+/// create it otherwise.
+///
+/// Like `use_state`, accessing occupied state protects it from the next sweep.
+#[track_caller]
+pub fn state_entry<T: 'static>() -> StateEntry<T> {
+    state_entry_in(default_store(), std::panic::Location::caller())
+}
+
+fn state_entry_in<T: 'static>(
+    store: Rc<RefCell<Store>>,
+    location: &'static std::panic::Location<'static>,
+) -> StateEntry<T> {
+    let id = Id::new();
+    check_hook_call::<T>(&store, id, location);
+
+    if state_exists_for_id::<Rc<RefCell<T>>>(&store, id) {
+        StateEntry::Occupied(State::new(&store, id))
+    } else {
+        StateEntry::Vacant(VacantEntry {
+            store,
+            id,
+            marker: std::marker::PhantomData,
+        })
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    fn set_count(n: i32) -> State<i32> {
-        root(|| use_state(|| n))
+/// The local state at a call site, as returned by `state_entry`.
+pub enum StateEntry<T> {
+    Occupied(State<T>),
+    Vacant(VacantEntry<T>),
+}
+
+/// A call site without local state of type `T`.
+pub struct VacantEntry<T> {
+    store: Rc<RefCell<Store>>,
+    id: Id,
+    marker: std::marker::PhantomData<T>,
+}
+
+impl<T: 'static> VacantEntry<T> {
+    /// Creates the local state with `value`.
+    pub fn insert(self, value: T) -> State<T> {
+        set_state_with_id::<Rc<RefCell<T>>>(&self.store, Rc::new(RefCell::new(value)), self.id);
+        State::new(&self.store, self.id)
     }
+}
 
-    #[test]
-    fn test_counts() {
-        let count = set_count(42);
-        assert_eq!(42, count.get(|n| *n));
-        let count = set_count(500); // noop because count already set
-        assert_eq!(42, count.get(|n| *n));
+/// Creates new local state with the given `init`, or provides a handle to the local state if it
+/// already exists.
+///
+/// Unlike `use_state`, this state is never cleared by `sweep`: it lives until it is removed
+/// explicitly with `State::take`.
+#[track_caller]
+pub fn use_ref<T: 'static, F: FnOnce() -> T>(init: F) -> State<T> {
+    use_ref_in(&default_store(), init, std::panic::Location::caller())
+}
 
-        sweep();
+fn use_ref_in<T: 'static, F: FnOnce() -> T>(
+    store: &Rc<RefCell<Store>>,
+    init: F,
+    location: &'static std::panic::Location<'static>,
+) -> State<T> {
+    let id = Id::new();
+    check_hook_call::<T>(store, id, location);
 
-        assert_eq!(42, count.get(|n| *n));
+    if !state_exists_for_id::<Rc<RefCell<T>>>(store, id) {
+        set_persistent_state_with_id::<Rc<RefCell<T>>>(store, Rc::new(RefCell::new(init())), id);
+    }
 
-        sweep();
-        sweep();
+    State::new(store, id)
+}
 
-        let count = set_count(500);
+/// Creates local state with `init`, which only ever runs on the first call at this call site, so
+/// it may have side effects such as registering resources.
+///
+/// The state is held like `use_ref` state, so only `State::take` and `clear_all` will cause
+/// `init` to run again.
+#[track_caller]
+pub fn use_state_init_once<T: 'static, F: FnOnce() -> T>(init: F) -> State<T> {
+    use_ref(init)
+}
+
+/// Creates local state holding the result of `compute`, which is only run again when `deps`
+/// differ from the ones passed on the previous call.
+#[track_caller]
+pub fn use_memo<T: 'static, D: PartialEq + 'static, F: FnOnce() -> T>(
+    deps: D,
+    compute: F,
+) -> State<T> {
+    let mut compute = Some(compute);
+    let memo = use_state(|| (compute.take().unwrap())());
+    let previous_deps = use_state(|| None::<D>);
+
+    if previous_deps.get(|previous| previous.as_ref() != Some(&deps)) {
+        // on the first call the value was already computed by `use_state`
+        if let Some(compute) = compute.take() {
+            memo.set_value(compute());
+        }
+        previous_deps.set_value(Some(deps));
+    }
+
+    memo
+}
+
+/// Creates local state with `init`, which is run again to reset the state whenever `deps` differ
+/// from the ones passed on the previous call.
+///
+/// This shares its implementation with `use_memo`, but is meant for state which is written
+/// between resets rather than derived from `deps`.
+#[track_caller]
+pub fn use_state_with_deps<T: 'static, D: PartialEq + 'static, F: FnOnce() -> T>(
+    deps: D,
+    init: F,
+) -> State<T> {
+    use_memo(deps, init)
+}
+
+/// Creates local state holding the result of `compute`, which runs on every call. The state is
+/// only written, notifying its subscribers, when the result differs from the stored value.
+///
+/// Unlike `use_memo` there are no deps to compare, so this suits computations which are cheaper
+/// to run than to track the inputs of.
+#[track_caller]
+pub fn use_computed<T: PartialEq + 'static, F: FnOnce() -> T>(compute: F) -> State<T> {
+    let mut value = Some(compute());
+    let state = use_state(|| value.take().unwrap());
+
+    // on the first call the value was already stored by `use_state`
+    if let Some(value) = value {
+        state.set_if_changed(value);
+    }
+
+    state
+}
+
+/// Creates local state holding `callback`, which is only replaced when `deps` differ from the
+/// ones passed on the previous call, so that repeated calls return the same `Rc`.
+#[track_caller]
+pub fn use_callback<Args, Ret, D, F>(deps: D, callback: F) -> State<Rc<dyn Fn(Args) -> Ret>>
+where
+    Args: 'static,
+    Ret: 'static,
+    D: PartialEq + 'static,
+    F: Fn(Args) -> Ret + 'static,
+{
+    use_memo(deps, || Rc::new(callback) as Rc<dyn Fn(Args) -> Ret>)
+}
+
+/// Creates local state with `init`, along with a dispatch function which updates it by applying
+/// `reducer` to each action it's given.
+#[track_caller]
+pub fn use_reducer<S: 'static, A, I: FnOnce() -> S, F: Fn(&mut S, A)>(
+    init: I,
+    reducer: F,
+) -> (State<S>, impl Fn(A)) {
+    let state = use_state(init);
+    let dispatch_state = state.clone();
+
+    (state, move |action| {
+        dispatch_state.set(|data| reducer(data, action))
+    })
+}
+
+/// Like `use_reducer`, but calls `middleware` with the current state and each action before the
+/// action is applied, e.g. for logging.
+#[track_caller]
+pub fn use_reducer_with_middleware<S, A, I, F, M>(
+    init: I,
+    reducer: F,
+    middleware: M,
+) -> (State<S>, impl Fn(A))
+where
+    S: 'static,
+    I: FnOnce() -> S,
+    F: Fn(&mut S, A),
+    M: Fn(&S, &A),
+{
+    use_reducer(init, move |state, action| {
+        middleware(state, &action);
+        reducer(state, action)
+    })
+}
+
+/// Returns the value passed on the previous call at this call site, or `None` on the first call.
+#[track_caller]
+pub fn use_previous<T: 'static>(current: T) -> Option<T> {
+    use_state(|| None).replace(Some(current))
+}
+
+/// Runs `effect` whenever `deps` differ from the ones passed on the previous call at this call
+/// site.
+///
+/// The closure returned by `effect` cleans it up: it's run before the effect runs again, or once
+/// the call site's state is cleared by `sweep`.
+#[track_caller]
+pub fn use_effect<D: PartialEq + 'static, F: FnOnce() -> Box<dyn FnOnce()>>(deps: D, effect: F) {
+    let state = use_state(|| None::<Effect<D>>);
+
+    let changed = state.get(|previous| {
+        previous
+            .as_ref()
+            .is_none_or(|previous| previous.deps != deps)
+    });
+
+    if changed {
+        // dropping the previous effect runs its cleanup
+        drop(state.replace(None));
+        state.set_value(Some(Effect {
+            deps,
+            cleanup: Some(effect()),
+        }));
+    }
+}
+
+/// Runs `effect` on the first call at this call site.
+///
+/// It isn't run again until the call site's state has been cleared by `sweep`, after which the
+/// next call counts as a first call again.
+#[track_caller]
+pub fn use_mount<F: FnOnce()>(effect: F) {
+    use_effect((), || {
+        effect();
+        Box::new(|| ())
+    })
+}
+
+/// Runs `cleanup` once the state of this call site is cleared by `sweep`.
+///
+/// Only the closure given on the first call is kept, later ones are dropped without being run.
+#[track_caller]
+pub fn use_unmount<F: FnOnce() + 'static>(cleanup: F) {
+    use_effect((), || Box::new(cleanup))
+}
+
+struct Effect<D> {
+    deps: D,
+    cleanup: Option<Box<dyn FnOnce()>>,
+}
+
+impl<D> Drop for Effect<D> {
+    fn drop(&mut self) {
+        if let Some(cleanup) = self.cleanup.take() {
+            cleanup();
+        }
+    }
+}
+
+/// Exchanges the values held by `a` and `b`, notifying the subscribers of both.
+///
+/// Like any other write, this counts as an access of both states.
+///
+/// If the state of one of the handles was already cleared from the store this is a move: the other
+/// call site receives the cleared handle's value, and its own value is left in the cleared handle,
+/// to be dropped along with it. Only the state which is still held by the store is accessed and
+/// has its subscribers notified.
+pub fn swap<T: 'static>(a: &State<T>, b: &State<T>) {
+    if Rc::ptr_eq(&a.data, &b.data) {
+        return;
+    }
+
+    let live = [(a, a.check_exists().is_ok()), (b, b.check_exists().is_ok())];
+
+    for (state, _) in live.iter().filter(|(_, live)| *live) {
+        state.mark();
+    }
+
+    std::mem::swap(&mut *a.borrow_data_mut(), &mut *b.borrow_data_mut());
+
+    for (state, _) in live.iter().filter(|(_, live)| *live) {
+        state.notify();
+    }
+}
+
+/// Calls `func` with references to the values held by both `a` and `b` at once.
+///
+/// # Panics
+///
+/// If the state of either handle has been cleared from the store since it was created.
+pub fn zip_get<A: 'static, B: 'static, F: FnOnce(&A, &B) -> R, R>(
+    a: &State<A>,
+    b: &State<B>,
+    func: F,
+) -> R {
+    a.check_exists()
+        .and_then(|_| b.check_exists())
+        .unwrap_or_else(|error| panic!("{}", error));
+
+    func(&a.borrow_data(), &b.borrow_data())
+}
+
+/// Runs `func`, deferring the notifications of subscribers to state in the default store until it
+/// returns, so that each is called at most once however many times its state was written.
+///
+/// Batches may be nested, in which case subscribers are notified when the outermost one returns.
+pub fn batch<F: FnOnce() -> R, R>(func: F) -> R {
+    let store = default_store();
+    store.borrow_mut().batch_depth += 1;
+
+    let _batch = Batch { store: &store };
+    func()
+}
+
+/// Runs `body`, turning any sweep of the default store it makes into a no-op, e.g. so that nested
+/// code can't clear state which is still being assembled.
+///
+/// Calls may be nested, in which case sweeping resumes when the outermost one returns.
+pub fn without_sweep<F: FnOnce() -> R, R>(body: F) -> R {
+    let store = default_store();
+    store.borrow_mut().sweep_suppression += 1;
+
+    let _suppression = SweepSuppression { store: &store };
+    body()
+}
+
+// ends a `without_sweep` when dropped, even if its closure panicked
+struct SweepSuppression<'a> {
+    store: &'a RefCell<Store>,
+}
+
+impl Drop for SweepSuppression<'_> {
+    fn drop(&mut self) {
+        self.store.borrow_mut().sweep_suppression -= 1;
+    }
+}
+
+// finishes a batch when dropped, even if its closure panicked
+struct Batch<'a> {
+    store: &'a RefCell<Store>,
+}
+
+impl Drop for Batch<'_> {
+    fn drop(&mut self) {
+        let pending = {
+            let mut store = self.store.borrow_mut();
+            store.batch_depth -= 1;
+
+            if store.batch_depth > 0 {
+                return;
+            }
+
+            std::mem::take(&mut store.pending_notifications)
+        };
+
+        if std::thread::panicking() {
+            return;
+        }
+
+        for (id, type_id) in pending {
+            notify_subscribers(self.store, id, type_id);
+        }
+    }
+}
+
+pub struct State<T> {
+    data: Rc<RefCell<T>>,
+    id: Option<Id>,
+    // the store holding the state, which controlled state doesn't have
+    store: Option<Rc<RefCell<Store>>>,
+    // the generation of the state in the store when this handle was created
+    generation: u64,
+}
+
+impl<T> std::fmt::Debug for State<T>
+where
+    T: std::fmt::Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({:#?})", self.data)
+    }
+}
+
+// handles are equal when they refer to the same call site in the same store
+impl<T> PartialEq for State<T> {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.store, &other.store) {
+            (Some(store), Some(other_store)) => {
+                self.id == other.id && Rc::ptr_eq(store, other_store)
+            }
+            _ => Rc::ptr_eq(&self.data, &other.data),
+        }
+    }
+}
+
+impl<T> Eq for State<T> {}
+
+impl<T> Hash for State<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        match self.id {
+            Some(id) => id.hash(state),
+            None => Rc::as_ptr(&self.data).hash(state),
+        }
+    }
+}
+
+impl<T> Clone for State<T> {
+    fn clone(&self) -> State<T> {
+        State::<T> {
+            data: self.data.clone(),
+            id: self.id,
+            store: self.store.clone(),
+            generation: self.generation,
+        }
+    }
+}
+
+impl<T> State<T>
+where
+    T: 'static,
+{
+    fn new(store: &Rc<RefCell<Store>>, id: Id) -> Self {
+        Self {
+            data: read_state_with_id::<Rc<RefCell<T>>, _, Rc<RefCell<T>>>(store, id, |x| x.clone()),
+            id: Some(id),
+            store: Some(store.clone()),
+            generation: store.borrow().generation_for::<Rc<RefCell<T>>>(&id),
+        }
+    }
+
+    pub fn controlled(data: T) -> Self {
+        Self {
+            data: Rc::new(RefCell::new(data)),
+            id: None,
+            store: None,
+            generation: 0,
+        }
+    }
+
+    // the store and id the state is rooted at, unless it's controlled
+    fn rooted(&self) -> Option<(&RefCell<Store>, Id)> {
+        self.store.as_deref().zip(self.id)
+    }
+
+    // like `rooted`, but `None` once the state has been cleared since this handle was created,
+    // so that a stale handle can't act on state initialized again at the same call site
+    fn current(&self) -> Option<(&RefCell<Store>, Id)> {
+        self.check_exists().ok()?;
+        self.rooted()
+    }
+
+    /// Returns the key the store holds the state's call site under, for keying parallel
+    /// `SecondaryMap`s, or `None` if the call site has no state (e.g. the state is controlled).
+    ///
+    /// The key stays the same until the call site no longer holds any state after a sweep, at
+    /// which point it may be reused for another call site.
+    pub fn raw_key(&self) -> Option<DefaultKey> {
+        self.rooted()
+            .and_then(|(store, id)| store.borrow().key_for(&id))
+    }
+
+    /// Returns the topo call id the state is rooted at, or `None` for controlled state.
+    pub fn call_id(&self) -> Option<topo::CallId> {
+        self.id.map(topo::CallId::from)
+    }
+
+    /// Returns a handle to the state which doesn't keep its value alive once it's cleared from
+    /// the store, e.g. for holding on to state whose call site may stop being used.
+    pub fn downgrade(&self) -> WeakState<T> {
+        WeakState {
+            data: Rc::downgrade(&self.data),
+            id: self.id,
+            store: self.store.as_ref().map(Rc::downgrade),
+            generation: self.generation,
+        }
+    }
+
+    /// Mutates the state with `func`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the state has been cleared from the store since this handle was created. See
+    /// `try_set` for a non-panicking version.
+    pub fn set<F: FnOnce(&mut T) -> U, U>(&self, func: F) -> U {
+        self.try_set(func)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Mutates the state with `func`, or returns an error if the state has been cleared from the
+    /// store since this handle was created.
+    pub fn try_set<F: FnOnce(&mut T) -> U, U>(&self, func: F) -> Result<U, HookError> {
+        self.check_exists()?;
+        self.mark();
+
+        let result = func(&mut self.borrow_data_mut());
+        self.notify();
+        Ok(result)
+    }
+
+    /// Replaces the state with `value`.
+    pub fn set_value(&self, value: T) {
+        // the store shares this allocation, so overwriting it in place is all that's needed
+        self.set(|data| *data = value);
+    }
+
+    /// Mutates the state with `func`, which returns whether it made a meaningful change.
+    pub fn update_if<F: FnOnce(&mut T) -> bool>(&self, func: F) -> bool {
+        self.try_update(func)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Like `update_if`, but returns an error rather than panicking if the state has been cleared
+    /// from the store since this handle was created.
+    pub fn try_update<F: FnOnce(&mut T) -> bool>(&self, func: F) -> Result<bool, HookError> {
+        self.check_exists()?;
+        self.mark();
+
+        let changed = func(&mut self.borrow_data_mut());
+
+        if changed {
+            self.notify();
+        }
+
+        Ok(changed)
+    }
+
+    /// Replaces the state with `value` if it differs from the current state, returning whether
+    /// it was replaced.
+    pub fn set_if_changed(&self, value: T) -> bool
+    where
+        T: PartialEq,
+    {
+        let changed = self.get(|data| *data != value);
+
+        if changed {
+            self.set_value(value);
+        }
+
+        changed
+    }
+
+    /// Replaces the state with `value`, returning the previous value.
+    pub fn replace(&self, value: T) -> T {
+        self.set(|data| std::mem::replace(data, value))
+    }
+
+    /// Registers `subscriber` to be called after each write to the state through `set`,
+    /// `set_value`, `replace` or the other setters.
+    ///
+    /// Subscribers are tracked per call site and type of state, so they are not called for
+    /// controlled state, nor for writes to state of another type at the same call site. They're
+    /// dropped along with the state when it's cleared from the store, and aren't called for state
+    /// initialized again at the same call site afterwards.
+    pub fn subscribe<F: Fn() + 'static>(&self, subscriber: F) -> SubscriptionHandle {
+        let subscription = self.current().map(|(store, id)| {
+            store
+                .borrow_mut()
+                .subscribe((id, Self::type_id()), Rc::new(subscriber))
+        });
+
+        SubscriptionHandle {
+            store: self.store.clone(),
+            key: self.id.map(|id| (id, Self::type_id())),
+            subscription,
+        }
+    }
+
+    fn check_exists(&self) -> Result<(), HookError> {
+        match self.rooted() {
+            Some((store, id)) if !state_exists_for_id::<Rc<RefCell<T>>>(store, id) => {
+                Err(HookError::StateMissing(id))
+            }
+            Some((store, id))
+                if store.borrow().generation_for::<Rc<RefCell<T>>>(&id) != self.generation =>
+            {
+                Err(HookError::Stale(id))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn borrow_data(&self) -> std::cell::Ref<'_, T> {
+        self.data
+            .try_borrow()
+            .unwrap_or_else(|_| self.already_in_use())
+    }
+
+    fn borrow_data_mut(&self) -> std::cell::RefMut<'_, T> {
+        self.data
+            .try_borrow_mut()
+            .unwrap_or_else(|_| self.already_in_use())
+    }
+
+    fn already_in_use(&self) -> ! {
+        panic!(
+            "State is already in use by an enclosing `get` or `set` of the same state ({:?}). \
+             Reads may be nested, but a write can't happen within another read or write.",
+            self.id
+        )
+    }
+
+    // writes count as an access, so that state set since the last sweep survives the next one
+    // without having to be read
+    fn mark(&self) {
+        if let Some((store, id)) = self.rooted() {
+            mark_state_with_id::<Rc<RefCell<T>>>(store, id);
+        }
+    }
+
+    fn notify(&self) {
+        if let Some((store, id)) = self.rooted() {
+            notify_subscribers(store, id, Self::type_id());
+        }
+    }
+
+    // the type the state is held as in the store, which also keys its subscribers
+    fn type_id() -> TypeId {
+        TypeId::of::<Rc<RefCell<T>>>()
+    }
+
+    /// Removes the state from the store, returning it if this was the last handle to it.
+    ///
+    /// A later `use_state` or `use_ref` at the same call site will initialize the state again.
+    /// Returns `None` without touching the store if the state has already been cleared from it
+    /// since this handle was created.
+    pub fn take(self) -> Option<T> {
+        if let Some((store, id)) = self.rooted() {
+            self.check_exists().ok()?;
+            remove_state_with_id::<Rc<RefCell<T>>>(store, id);
+        }
+
+        Rc::try_unwrap(self.data).ok().map(RefCell::into_inner)
+    }
+
+    /// Exempts the state from sweeping until `unpin` is called.
+    ///
+    /// This, `unpin`, `set_meta` and `on_clear` have no effect once the state has been cleared
+    /// from the store since this handle was created.
+    pub fn pin(&self) {
+        if let Some((store, id)) = self.current() {
+            pin_state_with_id::<Rc<RefCell<T>>>(store, id);
+        }
+    }
+
+    /// Returns pinned state to the sweep cycle, counting as an access for the current one.
+    ///
+    /// This has no effect on state which wasn't pinned, such as state from `use_ref`.
+    pub fn unpin(&self) {
+        if let Some((store, id)) = self.current() {
+            unpin_state_with_id::<Rc<RefCell<T>>>(store, id);
+        }
+    }
+
+    /// Labels the state's call site with `name` for `debug_dump` and `label_for`.
+    ///
+    /// Labels belong to the call site rather than the state, so they aren't swept: a label stays
+    /// in place when the state is cleared and initialized again, until `clear_all`. This has no
+    /// effect on controlled state.
+    pub fn label(&self, name: &'static str) {
+        if let Some((store, id)) = self.rooted() {
+            store.borrow_mut().labels.insert(id, name);
+        }
+    }
+
+    /// Attaches `meta` to the state, replacing any metadata of the same type. Metadata is dropped
+    /// along with the state, and belongs to the state rather than its call site, so it isn't
+    /// shared with state of another type at the same call site.
+    ///
+    /// This has no effect on controlled state.
+    pub fn set_meta<M: 'static>(&self, meta: M) {
+        if let Some((store, id)) = self.current() {
+            let mut store = store.borrow_mut();
+
+            if let Some(key) = store.key_for(&id) {
+                if !store.metadata.contains_key(key) {
+                    store.metadata.insert(key, HashMap::new());
+                }
+
+                store.metadata[key]
+                    .entry(TypeId::of::<Rc<RefCell<T>>>())
+                    .or_insert_with(anymap::Map::new)
+                    .insert(meta);
+            }
+        }
+    }
+
+    /// Returns a copy of the metadata of type `M` attached to the state with `set_meta`.
+    pub fn get_meta<M: Clone + 'static>(&self) -> Option<M> {
+        let (store, id) = self.current()?;
+        let store = store.borrow();
+
+        store
+            .key_for(&id)
+            .and_then(|key| store.metadata.get(key))
+            .and_then(|metadata| metadata.get(&TypeId::of::<Rc<RefCell<T>>>()))
+            .and_then(|metadata| metadata.get::<M>())
+            .cloned()
+    }
+
+    /// Registers `cleanup` to be run when the state is cleared by `sweep` or `clear_all`. It's
+    /// dropped without being run if the state is removed with `take` instead.
+    ///
+    /// This has no effect on controlled state, which is never held by the store.
+    pub fn on_clear<F: FnOnce() + 'static>(&self, cleanup: F) {
+        if let Some((store, id)) = self.current() {
+            store
+                .borrow_mut()
+                .register_cleanup::<Rc<RefCell<T>>>(&id, Box::new(cleanup));
+        }
+    }
+
+    /// Reads the state with `func`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the state has been cleared from the store since this handle was created. See
+    /// `try_get` for a non-panicking version.
+    pub fn get<F: FnOnce(&T) -> U, U>(&self, func: F) -> U {
+        self.try_get(func)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Reads the state with `func`, or returns an error if the state has been cleared from the
+    /// store (e.g. by `sweep` or `State::take`) since this handle was created.
+    pub fn try_get<F: FnOnce(&T) -> U, U>(&self, func: F) -> Result<U, HookError> {
+        self.check_exists()?;
+        Ok(func(&self.borrow_data()))
+    }
+
+    /// Returns an owned copy of the state.
+    ///
+    /// ```
+    /// let count = hooks::root(|| hooks::use_state(|| 42i32));
+    /// let n = count.get_cloned();
+    /// assert_eq!(42, n);
+    /// ```
+    pub fn get_cloned(&self) -> T
+    where
+        T: Clone,
+    {
+        self.get(|data| data.clone())
+    }
+
+    /// Awaits `fut` and replaces the state with its output.
+    ///
+    /// The state is only written once `fut` resolves, so the state has to still exist by then:
+    /// a host which keeps sweeping while the future is pending should keep the call site
+    /// accessed, or `pin` the state.
+    ///
+    /// ```
+    /// use std::future::Future;
+    /// use std::sync::Arc;
+    /// use std::task::{Context, Poll, Wake};
+    ///
+    /// struct Noop;
+    ///
+    /// impl Wake for Noop {
+    ///     fn wake(self: Arc<Self>) {}
+    /// }
+    ///
+    /// let count = hooks::root(|| hooks::use_state(|| 0));
+    /// let mut update = Box::pin(count.clone().set_async(async { 42 }));
+    ///
+    /// let waker = Arc::new(Noop).into();
+    /// let mut cx = Context::from_waker(&waker);
+    /// assert!(update.as_mut().poll(&mut cx).is_ready());
+    /// assert_eq!(42, count.get_copy());
+    /// ```
+    #[cfg(feature = "async")]
+    pub async fn set_async<Fut: std::future::Future<Output = T>>(self, fut: Fut) {
+        self.set_value(fut.await);
+    }
+
+    /// Returns a `Stream` which yields a copy of the state each time it's written.
+    ///
+    /// Only the latest value is kept: writes made while the consumer isn't polling are collapsed
+    /// into the one which follows them. The stream ends when it's polled after the state was
+    /// cleared from the store, and never yields for controlled state.
+    #[cfg(feature = "async")]
+    pub fn to_stream(self) -> StateStream<T>
+    where
+        T: Clone,
+    {
+        let pending = Rc::new(RefCell::new(PendingWrite::default()));
+        let subscriber_pending = pending.clone();
+
+        let subscription = self.subscribe(move || {
+            let mut pending = subscriber_pending.borrow_mut();
+            pending.written = true;
+
+            if let Some(waker) = pending.waker.take() {
+                waker.wake();
+            }
+        });
+
+        StateStream {
+            state: self,
+            pending,
+            subscription: Some(subscription),
+        }
+    }
+
+    /// Returns a copy of the state.
+    ///
+    /// ```
+    /// let visible = hooks::root(|| hooks::use_state(|| true));
+    /// visible.set_value(false);
+    /// assert!(!visible.get_copy());
+    /// ```
+    pub fn get_copy(&self) -> T
+    where
+        T: Copy,
+    {
+        self.get(|data| *data)
+    }
+}
+
+/// An error returned by the fallible `State` accessors.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HookError {
+    /// The state rooted at this call site no longer exists in the store.
+    StateMissing(Id),
+    /// The state this handle was created for was cleared, and the call site has since been
+    /// initialized again.
+    Stale(Id),
+}
+
+impl std::fmt::Display for HookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::StateMissing(id) => write!(f, "State does not exist. ({:?})", id),
+            Self::Stale(id) => write!(
+                f,
+                "State was cleared and re-initialized since this handle was created. ({:?})",
+                id
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HookError {}
+
+/// A handle to some local state which doesn't keep it alive, as returned by `State::downgrade`.
+pub struct WeakState<T> {
+    data: std::rc::Weak<RefCell<T>>,
+    id: Option<Id>,
+    store: Option<std::rc::Weak<RefCell<Store>>>,
+    generation: u64,
+}
+
+impl<T> std::fmt::Debug for WeakState<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WeakState").field("id", &self.id).finish()
+    }
+}
+
+impl<T> Clone for WeakState<T> {
+    fn clone(&self) -> Self {
+        Self {
+            data: self.data.clone(),
+            id: self.id,
+            store: self.store.clone(),
+            generation: self.generation,
+        }
+    }
+}
+
+impl<T: 'static> WeakState<T> {
+    /// Returns a handle to the state, or `None` if it has been cleared from the store since the
+    /// handle this was downgraded from was created.
+    ///
+    /// Like reading through a handle, this doesn't count as an access of the state.
+    pub fn upgrade(&self) -> Option<State<T>> {
+        let store = match &self.store {
+            Some(store) => Some(store.upgrade()?),
+            None => None,
+        };
+
+        let state = State {
+            data: self.data.upgrade()?,
+            id: self.id,
+            store,
+            generation: self.generation,
+        };
+
+        state.check_exists().ok().map(|_| state)
+    }
+}
+
+/// A subscription to writes of some local state, as returned by `State::subscribe`.
+///
+/// Dropping the handle doesn't end the subscription, which lasts until `unsubscribe` is called or
+/// the state is cleared from the store.
+#[must_use = "the subscriber can only be removed through its handle, before its state is cleared"]
+pub struct SubscriptionHandle {
+    store: Option<Rc<RefCell<Store>>>,
+    key: Option<SubscriberKey>,
+    subscription: Option<u64>,
+}
+
+impl std::fmt::Debug for SubscriptionHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SubscriptionHandle")
+            .field("id", &self.key.map(|(id, _)| id))
+            .field("subscription", &self.subscription)
+            .finish()
+    }
+}
+
+impl SubscriptionHandle {
+    /// Stops calling the subscriber. This is safe to call from within a subscriber.
+    pub fn unsubscribe(self) {
+        if let (Some(store), Some(key), Some(subscription)) =
+            (self.store, self.key, self.subscription)
+        {
+            store.borrow_mut().unsubscribe(&key, subscription);
+        }
+    }
+}
+
+/// A stream of the values written to some local state, as returned by `State::to_stream`.
+#[cfg(feature = "async")]
+pub struct StateStream<T> {
+    state: State<T>,
+    pending: Rc<RefCell<PendingWrite>>,
+    subscription: Option<SubscriptionHandle>,
+}
+
+#[cfg(feature = "async")]
+#[derive(Default)]
+struct PendingWrite {
+    written: bool,
+    waker: Option<std::task::Waker>,
+}
+
+#[cfg(feature = "async")]
+impl<T: Clone + 'static> futures_core::Stream for StateStream<T> {
+    type Item = T;
+
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<T>> {
+        if self.state.check_exists().is_err() {
+            return std::task::Poll::Ready(None);
+        }
+
+        let mut pending = self.pending.borrow_mut();
+
+        if !pending.written {
+            pending.waker = Some(cx.waker().clone());
+            return std::task::Poll::Pending;
+        }
+
+        pending.written = false;
+        std::task::Poll::Ready(Some(self.state.get_cloned()))
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T: Clone + 'static> StateStream<T> {
+    /// Waits for the next write to the state.
+    pub async fn next(&mut self) -> Option<T> {
+        std::future::poll_fn(|cx| {
+            futures_core::Stream::poll_next(std::pin::Pin::new(&mut *self), cx)
+        })
+        .await
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T> Drop for StateStream<T> {
+    fn drop(&mut self) {
+        if let Some(subscription) = self.subscription.take() {
+            subscription.unsubscribe();
+        }
+    }
+}
+
+type Subscriber = Rc<dyn Fn()>;
+
+// subscribers are kept per id and type of state, since state of several types may share an id
+type SubscriberKey = (Id, TypeId);
+
+fn notify_subscribers(store: &RefCell<Store>, id: Id, type_id: TypeId) {
+    let key = (id, type_id);
+
+    if store.borrow_mut().defer_notification(key) {
+        return;
+    }
+
+    // subscribers are cloned out first so that they may use the store themselves
+    let subscribers = store.borrow().subscribers_for(&key);
+
+    for subscriber in subscribers {
+        subscriber();
+    }
+}
+
+/// Enables checking, in debug builds, that each hook is always called for the same type of state.
+///
+/// Since ids are positional, a hook that's called for a different type than on a previous pass
+/// means that hooks were called conditionally or in a different order, which makes state end up
+/// at the wrong call sites. With checks enabled this panics instead. Checks have no effect in
+/// release builds.
+///
+/// Every `root` gives the hooks called in it the same ids, so e.g. `root(|| use_state(|| 0))` and
+/// `root(|| use_state(String::new))` share an id. Hooks are only compared with the others called
+/// under a `root` at the same call site, so such helpers don't panic, but a mismatch between
+/// hooks under roots at different call sites isn't caught.
+pub fn enable_hook_type_checks(enabled: bool) {
+    STORE.with(|store_refcell| store_refcell.borrow_mut().check_hook_types = enabled);
+}
+
+/// Enables warnings, in debug builds, when the hook called at a call site differs from the one
+/// first called there since the call site last held state, e.g. because hooks were called
+/// conditionally.
+///
+/// Since ids are positional, such a hook reads state which was created for another one. Unlike
+/// `enable_hook_type_checks`, this compares where hooks were called from as well as the type of
+/// their state, and warns rather than panicking. Like those checks, it only compares hooks called
+/// under a `root` at the same call site. Each call site is warned about once, by logging
+/// the diverging call sites to stderr and keeping the warning for `take_hook_order_warnings`.
+/// Checks have no effect in release builds.
+pub fn enable_hook_order_checks(enabled: bool) {
+    STORE.with(|store_refcell| store_refcell.borrow_mut().check_hook_order = enabled);
+}
+
+/// Returns the warnings of the checks enabled by `enable_hook_order_checks` since the last call,
+/// oldest first.
+pub fn take_hook_order_warnings() -> Vec<String> {
+    STORE.with(|store_refcell| std::mem::take(&mut store_refcell.borrow_mut().hook_order_warnings))
+}
+
+// a hook call, as recorded for the checks enabled by `enable_hook_type_checks` and
+// `enable_hook_order_checks`
+#[cfg(debug_assertions)]
+#[derive(Clone, Copy)]
+struct HookCall {
+    type_id: TypeId,
+    type_name: &'static str,
+    location: &'static std::panic::Location<'static>,
+    // the call site of the `root` the hook was called under
+    root: Option<&'static std::panic::Location<'static>>,
+}
+
+// compares a hook call at `id` against the first one there, if checks are enabled
+fn check_hook_call<T: 'static>(
+    _store: &RefCell<Store>,
+    _id: Id,
+    _location: &'static std::panic::Location<'static>,
+) {
+    #[cfg(debug_assertions)]
+    {
+        let (check_types, check_order) = {
+            let store = _store.borrow();
+            (store.check_hook_types, store.check_hook_order)
+        };
+
+        if !check_types && !check_order {
+            return;
+        }
+
+        let call = HookCall {
+            type_id: TypeId::of::<T>(),
+            type_name: std::any::type_name::<T>(),
+            location: _location,
+            root: current_root(),
+        };
+        let first = match _store.borrow_mut().record_hook_call(&_id, call) {
+            Some(first) => first,
+            None => return,
+        };
+
+        if check_types && first.type_id != call.type_id {
+            panic!(
+                "A hook at {:?} was called for state of type `{}`, but it was first called for \
+                 `{}`. Hooks must be called unconditionally and in the same order each time.",
+                _id, call.type_name, first.type_name
+            );
+        }
+
+        if check_order {
+            let warning = format!(
+                "the hook at {:?} was called from {} for `{}`, but it was first called from {} \
+                 for `{}`. Hooks must be called unconditionally and in the same order each time.",
+                _id, call.location, call.type_name, first.location, first.type_name
+            );
+            _store.borrow_mut().warn_hook_order(&_id, warning);
+        }
+    }
+}
+
+fn set_state_with_id<T: 'static>(store: &RefCell<Store>, data: T, current_id: Id) {
+    store.borrow_mut().set_state_with_id::<T>(data, &current_id);
+}
+
+fn set_persistent_state_with_id<T: 'static>(store: &RefCell<Store>, data: T, current_id: Id) {
+    store
+        .borrow_mut()
+        .set_persistent_state_with_id::<T>(data, &current_id);
+}
+
+fn mark_state_with_id<T: 'static>(store: &RefCell<Store>, current_id: Id) {
+    store.borrow_mut().mark_state_with_id::<T>(&current_id);
+}
+
+fn pin_state_with_id<T: 'static>(store: &RefCell<Store>, current_id: Id) {
+    store.borrow_mut().pin_state_with_id::<T>(&current_id);
+}
+
+fn unpin_state_with_id<T: 'static>(store: &RefCell<Store>, current_id: Id) {
+    store.borrow_mut().unpin_state_with_id::<T>(&current_id);
+}
+
+fn state_exists_for_id<T: 'static>(store: &RefCell<Store>, id: Id) -> bool {
+    store.borrow().state_exists_with_id::<T>(id)
+}
+
+fn state_marked_with_id<T: 'static>(store: &RefCell<Store>, id: Id) -> bool {
+    store.borrow().state_marked_with_id::<T>(id)
+}
+
+fn remove_state_with_id<T: 'static>(store: &RefCell<Store>, id: Id) -> Option<T> {
+    store.borrow_mut().remove_state_with_id::<T>(&id)
+}
+
+// state which only lives in the reverse buffer is first moved into the active one, which takes a
+// mutable borrow of the store. `func` then runs under a shared borrow, so it may read state which
+// is already marked, including at the same id, but not state which still has to be marked
+fn read_state_with_id<T: 'static, F: FnOnce(&T) -> R, R>(
+    store: &RefCell<Store>,
+    id: Id,
+    func: F,
+) -> R {
+    if !state_marked_with_id::<T>(store, id) {
+        mark_state_with_id::<T>(store, id);
+    }
+
+    store
+        .borrow()
+        .with_state_ref::<T, F, R>(&id, func)
+        .expect("State does not exist.")
+}
+
+/// Identifies a call site which may hold state.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Hash)]
+pub struct Id {
+    id: topo::CallId,
+}
+
+impl From<Id> for topo::CallId {
+    fn from(id: Id) -> Self {
+        id.id
+    }
+}
+
+impl Id {
+    #[topo::nested]
+    fn new() -> Self {
+        Self {
+            id: topo::CallId::current(),
+        }
+    }
+}
+
+// drops the metadata attached to the state of type `type_id` for `key`
+fn remove_metadata(metadata: &mut Metadata, key: DefaultKey, type_id: TypeId) {
+    if let Some(by_type) = metadata.get_mut(key) {
+        by_type.remove(&type_id);
+
+        if by_type.is_empty() {
+            metadata.remove(key);
+        }
+    }
+}
+
+// the store's record of an id which holds state
+#[derive(Clone)]
+struct IdEntry {
+    id: Id,
+    // the generation of the state of each type at this id, changed whenever that state is removed
+    // so that handles to it can tell they're stale. Types without an entry are at `generation`
+    generation: u64,
+    generations: HashMap<TypeId, u64>,
+    // the types of state at this id which were exempted from sweeping with `State::pin`
+    pinned: HashSet<TypeId>,
+    // orders entries by when they were created, since neither keys nor ids are ordered
+    created: u64,
+    // the sweep cycle during which the id's state was last accessed
+    last_marked: u64,
+    // the first hook called at this id
+    #[cfg(debug_assertions)]
+    first_hook: Option<HookCall>,
+    // whether a later hook differing from the first one was warned about
+    #[cfg(debug_assertions)]
+    order_warned: bool,
+}
+
+#[derive(Clone, Copy)]
+enum Mode {
+    A,
+    B,
+}
+
+impl Mode {
+    fn reverse(&self) -> Self {
+        match self {
+            Self::A => Self::B,
+            Self::B => Self::A,
+        }
+    }
+}
+
+// type-erased operations over the `SecondaryMap`s registered for a particular state type
+#[derive(Clone)]
+struct TypeInfo {
+    type_id: TypeId,
+    name: &'static str,
+    contains_key: fn(&anymap::Map<dyn Any>, DefaultKey) -> bool,
+    len: fn(&anymap::Map<dyn Any>) -> Option<usize>,
+    move_key: fn(&mut anymap::Map<dyn Any>, &mut anymap::Map<dyn Any>, DefaultKey),
+    shrink: fn(&mut anymap::Map<dyn Any>),
+    // deep-clones the state of this type from one buffer into another, for types registered
+    // with `register_clone`
+    clone_map: Option<CloneMap>,
+}
+
+type Metadata = SecondaryMap<DefaultKey, HashMap<TypeId, anymap::Map<dyn Any>>>;
+
+type CloneMap = fn(&anymap::Map<dyn Any>, &mut anymap::Map<dyn Any>);
+
+impl TypeInfo {
+    fn of<T: 'static>() -> Self {
+        Self {
+            type_id: TypeId::of::<T>(),
+            name: std::any::type_name::<T>(),
+            contains_key: datamap_contains_key::<T>,
+            len: |datamap| {
+                datamap
+                    .get::<SecondaryMap<DefaultKey, T>>()
+                    .map(SecondaryMap::len)
+            },
+            move_key: |from, to, key| {
+                let data = from
+                    .get_mut::<SecondaryMap<DefaultKey, T>>()
+                    .and_then(|secondary_map| secondary_map.remove(key));
+
+                if let Some(data) = data {
+                    to.entry::<SecondaryMap<DefaultKey, T>>()
+                        .or_insert_with(SecondaryMap::new)
+                        .insert(key, data);
+                }
+            },
+            shrink: |datamap| {
+                let is_empty = match datamap.get_mut::<SecondaryMap<DefaultKey, T>>() {
+                    // `SecondaryMap` has no `shrink_to_fit`, but collecting its entries into a
+                    // new one only allocates as much as they need
+                    Some(secondary_map) => {
+                        *secondary_map = secondary_map.drain().collect();
+                        secondary_map.is_empty()
+                    }
+                    None => false,
+                };
+
+                if is_empty {
+                    datamap.remove::<SecondaryMap<DefaultKey, T>>();
+                }
+            },
+            clone_map: None,
+        }
+    }
+}
+
+fn clone_states<T: Clone + 'static>(from: &anymap::Map<dyn Any>, to: &mut anymap::Map<dyn Any>) {
+    if let Some(secondary_map) = from.get::<SecondaryMap<DefaultKey, Rc<RefCell<T>>>>() {
+        let cloned: SecondaryMap<_, _> = secondary_map
+            .iter()
+            .map(|(key, data)| (key, Rc::new(RefCell::new(data.borrow().clone()))))
+            .collect();
+        to.insert(cloned);
+    }
+}
+
+fn datamap_contains_key<T: 'static>(datamap: &anymap::Map<dyn Any>, key: DefaultKey) -> bool {
+    datamap
+        .get::<SecondaryMap<DefaultKey, T>>()
+        .is_some_and(|secondary_map| secondary_map.contains_key(key))
+}
+
+// a closure registered to run when some state is cleared
+struct Cleanup {
+    // the type of the state this cleanup belongs to, and whether a data buffer holds it
+    type_id: TypeId,
+    held_by: fn(&anymap::Map<dyn Any>, DefaultKey) -> bool,
+    cleanup: Box<dyn FnOnce()>,
+}
+
+// state removed from the store, along with the cleanups registered for it
+#[must_use]
+struct Cleared {
+    data: Vec<anymap::Map<dyn Any>>,
+    cleanups: Vec<Box<dyn FnOnce()>>,
+    report: SweepReport,
+}
+
+impl Cleared {
+    fn nothing() -> Self {
+        Self {
+            data: Vec::new(),
+            cleanups: Vec::new(),
+            report: SweepReport::default(),
+        }
+    }
+
+    // must be called once the store is no longer borrowed, since both cleanups and dropping
+    // state (e.g. an effect) may use hooks
+    fn finish(self) -> SweepReport {
+        for cleanup in self.cleanups {
+            cleanup();
+        }
+        drop(self.data);
+
+        self.report
+    }
+}
+
+struct Store {
+    data_a: anymap::Map<dyn Any>,
+    data_b: anymap::Map<dyn Any>,
+    // state which is exempt from sweeping
+    data_persistent: anymap::Map<dyn Any>,
+    mode: Mode,
+    keys_by_id: HashMap<Id, DefaultKey, IdHasher>,
+    ids: DenseSlotMap<DefaultKey, IdEntry>,
+    next_generation: u64,
+    types: Vec<TypeInfo>,
+    cleanups: SecondaryMap<DefaultKey, Vec<Cleanup>>,
+    // names given to call sites with `State::label`, which outlive the state itself
+    labels: HashMap<Id, &'static str, IdHasher>,
+    // metadata attached with `State::set_meta`, by the type of the state it's attached to
+    metadata: Metadata,
+    subscribers: HashMap<SubscriberKey, Vec<(u64, Subscriber)>, IdHasher>,
+    next_subscription: u64,
+    // the number of `batch` calls currently running, and the ids written during them
+    batch_depth: usize,
+    pending_notifications: Vec<SubscriberKey>,
+    // the number of `without_sweep` calls currently running
+    sweep_suppression: usize,
+    policy: Box<dyn SweepPolicy>,
+    // the number of sweeps so far
+    cycle: u64,
+    check_hook_types: bool,
+    check_hook_order: bool,
+    hook_order_warnings: Vec<String>,
+}
+
+impl Store {
+    pub fn new() -> Store {
+        Store::with_policy(Box::new(ClearUnaccessed))
+    }
+
+    pub fn with_policy(policy: Box<dyn SweepPolicy>) -> Store {
+        Store {
+            data_a: anymap::Map::new(),
+            data_b: anymap::Map::new(),
+            data_persistent: anymap::Map::new(),
+            ids: DenseSlotMap::new(),
+            next_generation: 0,
+            keys_by_id: HashMap::default(),
+            mode: Mode::A,
+            types: Vec::new(),
+            cleanups: SecondaryMap::new(),
+            labels: HashMap::default(),
+            metadata: SecondaryMap::new(),
+            subscribers: HashMap::default(),
+            next_subscription: 0,
+            batch_depth: 0,
+            pending_notifications: Vec::new(),
+            sweep_suppression: 0,
+            policy,
+            cycle: 0,
+            check_hook_types: false,
+            check_hook_order: false,
+            hook_order_warnings: Vec::new(),
+        }
+    }
+
+    // copies the store, with deep clones of the state of each type registered with
+    // `register_clone` but without any other state, subscribers or cleanups
+    pub fn fork(&self) -> Store {
+        let mut fork = Store::new();
+        fork.mode = self.mode;
+        fork.keys_by_id = self.keys_by_id.clone();
+        fork.ids = self.ids.clone();
+        fork.next_generation = self.next_generation;
+        fork.labels = self.labels.clone();
+        fork.cycle = self.cycle;
+        fork.check_hook_types = self.check_hook_types;
+        fork.check_hook_order = self.check_hook_order;
+
+        for type_info in &self.types {
+            if let Some(clone_map) = type_info.clone_map {
+                clone_map(&self.data_a, &mut fork.data_a);
+                clone_map(&self.data_b, &mut fork.data_b);
+                clone_map(&self.data_persistent, &mut fork.data_persistent);
+            }
+        }
+
+        fork.types = self.types.clone();
+        fork.remove_unused_ids();
+        fork
+    }
+
+    pub fn register_clone<T: Clone + 'static>(&mut self) {
+        self.register_type::<Rc<RefCell<T>>>();
+
+        for type_info in &mut self.types {
+            if type_info.type_id == TypeId::of::<Rc<RefCell<T>>>() {
+                type_info.clone_map = Some(clone_states::<T>);
+            }
+        }
+    }
+
+    pub fn sweep(&mut self) -> Cleared {
+        if self.sweep_suppression > 0 {
+            return Cleared::nothing();
+        }
+
+        let mut swept = match self.mode {
+            Mode::A => {
+                self.mode = Mode::B;
+                std::mem::replace(&mut self.data_b, anymap::Map::new())
+            }
+            Mode::B => {
+                self.mode = Mode::A;
+                std::mem::replace(&mut self.data_a, anymap::Map::new())
+            }
+        };
+
+        self.cycle += 1;
+        self.retain_by_policy(&mut swept);
+        self.clear_swept(swept)
+    }
+
+    pub fn sweep_older_than(&mut self, cycles: u64) -> Cleared {
+        if self.sweep_suppression > 0 {
+            return Cleared::nothing();
+        }
+
+        let cycle = self.cycle;
+        let expired: Vec<_> = self
+            .ids
+            .iter()
+            .filter(|(_, entry)| cycle - entry.last_marked > cycles)
+            .map(|(key, _)| key)
+            .collect();
+
+        let mut swept = anymap::Map::new();
+
+        for datamap in [&mut self.data_a, &mut self.data_b] {
+            for key in &expired {
+                for type_info in &self.types {
+                    (type_info.move_key)(datamap, &mut swept, *key);
+                }
+            }
+        }
+
+        self.clear_swept(swept)
+    }
+
+    fn clear_swept(&mut self, swept: anymap::Map<dyn Any>) -> Cleared {
+        let cleanups = self.take_cleanups_held_by(&swept);
+        let report = SweepReport {
+            ids_cleared: self.bump_generations_held_by(&swept),
+            maps_cleared: self.count_maps_holding_state(&swept),
+        };
+        self.remove_unused_ids();
+
+        Cleared {
+            data: vec![swept],
+            cleanups,
+            report,
+        }
+    }
+
+    // maps left empty, e.g. after all of their state was marked into the other buffer, don't count
+    fn count_maps_holding_state(&self, datamap: &anymap::Map<dyn Any>) -> usize {
+        self.types
+            .iter()
+            .filter(|type_info| (type_info.len)(datamap).is_some_and(|len| len > 0))
+            .count()
+    }
+
+    pub fn clear_all(&mut self) -> Cleared {
+        let report = SweepReport {
+            ids_cleared: self.ids.len(),
+            maps_cleared: [&self.data_a, &self.data_b, &self.data_persistent]
+                .iter()
+                .map(|datamap| self.count_maps_holding_state(datamap))
+                .sum(),
+        };
+
+        self.keys_by_id.clear();
+        self.ids.clear();
+        self.subscribers.clear();
+        self.metadata.clear();
+        self.labels.clear();
+        self.mode = Mode::A;
+
+        let cleanups = std::mem::replace(&mut self.cleanups, SecondaryMap::new())
+            .into_iter()
+            .flat_map(|(_, cleanups)| cleanups)
+            .map(|cleanup| cleanup.cleanup)
+            .collect();
+
+        Cleared {
+            data: vec![
+                std::mem::replace(&mut self.data_a, anymap::Map::new()),
+                std::mem::replace(&mut self.data_b, anymap::Map::new()),
+                std::mem::replace(&mut self.data_persistent, anymap::Map::new()),
+            ],
+            cleanups,
+            report,
+        }
+    }
+
+    pub fn register_cleanup<T: 'static>(&mut self, current_id: &Id, cleanup: Box<dyn FnOnce()>) {
+        if let Some(key) = self.key_for(current_id) {
+            let cleanup = Cleanup {
+                type_id: TypeId::of::<T>(),
+                held_by: datamap_contains_key::<T>,
+                cleanup,
+            };
+
+            match self.cleanups.get_mut(key) {
+                Some(cleanups) => cleanups.push(cleanup),
+                None => {
+                    self.cleanups.insert(key, vec![cleanup]);
+                }
+            }
+        }
+    }
+
+    pub fn subscribe(&mut self, key: SubscriberKey, subscriber: Subscriber) -> u64 {
+        let subscription = self.next_subscription;
+        self.next_subscription += 1;
+
+        self.subscribers
+            .entry(key)
+            .or_default()
+            .push((subscription, subscriber));
+
+        subscription
+    }
+
+    pub fn unsubscribe(&mut self, key: &SubscriberKey, subscription: u64) {
+        if let Some(subscribers) = self.subscribers.get_mut(key) {
+            subscribers.retain(|(existing, _)| *existing != subscription);
+
+            if subscribers.is_empty() {
+                self.subscribers.remove(key);
+            }
+        }
+    }
+
+    pub fn subscribers_for(&self, key: &SubscriberKey) -> Vec<Subscriber> {
+        self.subscribers
+            .get(key)
+            .map_or_else(Vec::new, |subscribers| {
+                subscribers
+                    .iter()
+                    .map(|(_, subscriber)| subscriber.clone())
+                    .collect()
+            })
+    }
+
+    // returns whether notifying the subscribers of `key` has to wait for a batch to finish
+    fn defer_notification(&mut self, key: SubscriberKey) -> bool {
+        if self.batch_depth == 0 {
+            return false;
+        }
+
+        if !self.pending_notifications.contains(&key) {
+            self.pending_notifications.push(key);
+        }
+
+        true
+    }
+
+    fn take_cleanups_held_by(&mut self, datamap: &anymap::Map<dyn Any>) -> Vec<Box<dyn FnOnce()>> {
+        let mut taken = Vec::new();
+
+        for (key, cleanups) in self.cleanups.iter_mut() {
+            let (held, kept): (Vec<_>, Vec<_>) = std::mem::take(cleanups)
+                .into_iter()
+                .partition(|cleanup| (cleanup.held_by)(datamap, key));
+
+            *cleanups = kept;
+            taken.extend(held.into_iter().map(|cleanup| cleanup.cleanup));
+        }
+
+        self.cleanups.retain(|_, cleanups| !cleanups.is_empty());
+        taken
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        self.keys_by_id.reserve(additional);
+        self.ids.reserve(additional);
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.keys_by_id.shrink_to_fit();
+        self.subscribers.shrink_to_fit();
+        self.cleanups = self.cleanups.drain().collect();
+        self.metadata = self.metadata.drain().collect();
+
+        for type_info in &self.types {
+            for datamap in [
+                &mut self.data_a,
+                &mut self.data_b,
+                &mut self.data_persistent,
+            ] {
+                (type_info.shrink)(datamap);
+            }
+        }
+    }
+
+    pub fn reserve_for<T: 'static>(&mut self, additional: usize) {
+        let capacity = self.ids.len() + additional;
+
+        for mode in [Mode::A, Mode::B] {
+            self.get_mut_secondarymap::<T>(mode).set_capacity(capacity);
+        }
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        StoreStats {
+            tracked_ids: self.ids.len(),
+            maps_a: self.data_a.len(),
+            maps_b: self.data_b.len(),
+            maps_persistent: self.data_persistent.len(),
+            phase: self.mode.into(),
+        }
+    }
+
+    pub fn live_states<T: Clone + 'static>(&self) -> Vec<(Id, T)> {
+        let mut states = [
+            self.get_secondarymap::<T>(self.mode),
+            self.get_persistent_secondarymap::<T>(),
+        ]
+        .iter()
+        .flatten()
+        .flat_map(|secondary_map| secondary_map.iter())
+        .filter_map(|(key, data)| self.ids.get(key).map(|entry| (entry, data.clone())))
+        .collect::<Vec<_>>();
+
+        states.sort_by_key(|(entry, _)| entry.created);
+        states
+            .into_iter()
+            .map(|(entry, data)| (entry.id, data))
+            .collect()
+    }
+
+    pub fn marked_ids(&self) -> Vec<Id> {
+        let active = self.get_datamap(self.mode);
+
+        self.ids
+            .iter()
+            .filter(|(key, _)| {
+                self.types
+                    .iter()
+                    .any(|type_info| (type_info.contains_key)(active, *key))
+            })
+            .map(|(_, entry)| entry.id)
+            .collect()
+    }
+
+    pub fn count_states<T: 'static>(&self) -> usize {
+        [
+            self.get_secondarymap::<T>(Mode::A),
+            self.get_secondarymap::<T>(Mode::B),
+            self.get_persistent_secondarymap::<T>(),
+        ]
+        .iter()
+        .flatten()
+        .flat_map(|secondary_map| secondary_map.keys())
+        .collect::<HashSet<_>>()
+        .len()
+    }
+
+    pub fn debug_dump(&self) -> String {
+        let mut dump = format!("tracked ids: {}\n", self.ids.len());
+
+        let active = |mode| match (mode, self.mode) {
+            (Mode::A, Mode::A) | (Mode::B, Mode::B) => " (active)",
+            _ => "",
+        };
+        let buffers = [
+            (format!("buffer A{}", active(Mode::A)), &self.data_a),
+            (format!("buffer B{}", active(Mode::B)), &self.data_b),
+            ("persistent".to_string(), &self.data_persistent),
+        ];
+
+        for (name, datamap) in buffers {
+            dump.push_str(&format!("{}: {} maps\n", name, datamap.len()));
+
+            for type_info in &self.types {
+                if let Some(len) = (type_info.len)(datamap) {
+                    dump.push_str(&format!("  {}: {} entries\n", type_info.name, len));
+                }
+            }
+        }
+
+        if !self.labels.is_empty() {
+            let mut labels: Vec<_> = self.labels.iter().collect();
+            labels.sort_by_key(|(_, label)| **label);
+            dump.push_str(&format!("labels: {}\n", labels.len()));
+
+            for (id, label) in labels {
+                let held: Vec<_> = self
+                    .key_for(id)
+                    .map(|key| {
+                        self.types
+                            .iter()
+                            .filter(|type_info| {
+                                [&self.data_a, &self.data_b, &self.data_persistent]
+                                    .iter()
+                                    .any(|datamap| (type_info.contains_key)(datamap, key))
+                            })
+                            .map(|type_info| type_info.name)
+                            .collect()
+                    })
+                    .unwrap_or_default();
+
+                if held.is_empty() {
+                    dump.push_str(&format!("  {}: no state\n", label));
+                } else {
+                    dump.push_str(&format!("  {}: {}\n", label, held.join(", ")));
+                }
+            }
+        }
+
+        dump
+    }
+
+    pub fn generation_for<T: 'static>(&self, id: &Id) -> u64 {
+        self.key_for(id)
+            .and_then(|key| self.ids.get(key))
+            .map_or(0, |entry| {
+                entry
+                    .generations
+                    .get(&TypeId::of::<T>())
+                    .copied()
+                    .unwrap_or(entry.generation)
+            })
+    }
+
+    fn new_generation(&mut self) -> u64 {
+        self.next_generation += 1;
+        self.next_generation
+    }
+
+    // called whenever state of type `type_id` is removed for `key`, so that its handles become
+    // stale and its metadata and subscribers are dropped along with it. State of other types at
+    // the same id is left alone
+    fn bump_generation(&mut self, key: DefaultKey, type_id: TypeId) {
+        let generation = self.new_generation();
+        remove_metadata(&mut self.metadata, key, type_id);
+
+        if let Some(entry) = self.ids.get_mut(key) {
+            entry.generations.insert(type_id, generation);
+            entry.pinned.remove(&type_id);
+            self.subscribers.remove(&(entry.id, type_id));
+        }
+    }
+
+    // moves state which the policy keeps from `swept` into the new reverse buffer
+    fn retain_by_policy(&mut self, swept: &mut anymap::Map<dyn Any>) {
+        let types = &self.types;
+        let policy = &mut self.policy;
+
+        let kept: Vec<_> = self
+            .ids
+            .iter()
+            .filter(|(key, _)| {
+                types
+                    .iter()
+                    .any(|type_info| (type_info.contains_key)(swept, *key))
+            })
+            .filter(|(_, entry)| !policy.should_clear(entry.id))
+            .map(|(key, _)| key)
+            .collect();
+
+        let reverse = match self.mode {
+            Mode::A => &mut self.data_b,
+            Mode::B => &mut self.data_a,
+        };
+
+        for key in kept {
+            for type_info in types {
+                (type_info.move_key)(swept, reverse, key);
+            }
+        }
+    }
+
+    // returns the number of ids which held state in `datamap`
+    fn bump_generations_held_by(&mut self, datamap: &anymap::Map<dyn Any>) -> usize {
+        let held: Vec<_> = self
+            .ids
+            .keys()
+            .filter(|key| {
+                self.types
+                    .iter()
+                    .any(|type_info| (type_info.contains_key)(datamap, *key))
+            })
+            .collect();
+
+        for key in &held {
+            let cleared: Vec<_> = self
+                .types
+                .iter()
+                .filter(|type_info| (type_info.contains_key)(datamap, *key))
+                .map(|type_info| type_info.type_id)
+                .collect();
+
+            for type_id in cleared {
+                self.bump_generation(*key, type_id);
+            }
+        }
+
+        held.len()
+    }
+
+    // drops the key mappings of ids which no longer hold state of any type
+    fn remove_unused_ids(&mut self) {
+        let datamaps = [&self.data_a, &self.data_b, &self.data_persistent];
+        let types = &self.types;
+        let keys_by_id = &mut self.keys_by_id;
+        let cleanups = &mut self.cleanups;
+        let metadata = &mut self.metadata;
+        let mut removed = Vec::new();
+
+        self.ids.retain(|key, entry| {
+            let used = types.iter().any(|type_info| {
+                datamaps
+                    .iter()
+                    .any(|datamap| (type_info.contains_key)(datamap, key))
+            });
+
+            if !used {
+                keys_by_id.remove(&entry.id);
+                // the state was removed some other way, e.g. with `State::take`
+                cleanups.remove(key);
+                metadata.remove(key);
+                removed.push(entry.id);
+            }
+
+            used
+        });
+
+        if !removed.is_empty() {
+            self.subscribers.retain(|(id, _), _| !removed.contains(id));
+        }
+    }
+
+    pub fn state_exists_with_id<T: 'static>(&self, id: Id) -> bool {
+        self.state_exists::<T>(self.mode, id)
+            || self.state_exists::<T>(self.mode.reverse(), id)
+            || self.persistent_state_exists::<T>(id)
+    }
+
+    // whether the state is in the active buffer, i.e. it will survive the next sweep
+    pub fn state_marked_with_id<T: 'static>(&self, id: Id) -> bool {
+        self.state_exists::<T>(self.mode, id)
+    }
+
+    pub fn remove_state_with_id<T: 'static>(&mut self, current_id: &Id) -> Option<T> {
+        let key = self.key_for(current_id)?;
+
+        // state which wasn't accessed since the last sweep only lives in the reverse buffer
+        let reverse = self.mode.reverse();
+        let removed = self
+            .get_mut_secondarymap::<T>(self.mode)
+            .remove(key)
+            .or_else(|| self.get_mut_secondarymap::<T>(reverse).remove(key))
+            .or_else(|| self.get_mut_persistent_secondarymap::<T>().remove(key));
+
+        if removed.is_some() {
+            self.bump_generation(key, TypeId::of::<T>());
+
+            // the state wasn't cleared, so its cleanups are dropped without running
+            if let Some(cleanups) = self.cleanups.get_mut(key) {
+                cleanups.retain(|cleanup| cleanup.type_id != TypeId::of::<T>());
+
+                if cleanups.is_empty() {
+                    self.cleanups.remove(key);
+                }
+            }
+        }
+
+        removed
+    }
+
+    pub fn with_state_ref<T: 'static, F: FnOnce(&T) -> R, R>(
+        &self,
+        current_id: &Id,
+        func: F,
+    ) -> Option<R> {
+        let key = self.key_for(current_id)?;
+
+        self.get_secondarymap::<T>(self.mode)
+            .and_then(|secondary_map| secondary_map.get(key))
+            .or_else(|| {
+                self.get_persistent_secondarymap::<T>()
+                    .and_then(|secondary_map| secondary_map.get(key))
+            })
+            .map(func)
+    }
+
+    pub fn set_state_with_id<T: 'static>(&mut self, data: T, current_id: &Id) {
+        let key = self.key_for_or_insert(current_id);
+        self.get_mut_secondarymap::<T>(self.mode).insert(key, data);
+        self.touch(key);
+    }
+
+    pub fn set_persistent_state_with_id<T: 'static>(&mut self, data: T, current_id: &Id) {
+        let key = self.key_for_or_insert(current_id);
+        self.get_mut_persistent_secondarymap::<T>()
+            .insert(key, data);
+    }
+
+    pub fn mark_state_with_id<T: 'static>(&mut self, current_id: &Id) {
+        // state which is already marked, or exempt from sweeping, stays where it is
+        if !self.state_exists::<T>(self.mode.reverse(), *current_id) {
+            return;
+        }
+
+        if let Some(key) = self.key_for(current_id) {
+            let data = self
+                .get_mut_secondarymap::<T>(self.mode.reverse())
+                .remove(key)
+                .unwrap();
+            self.get_mut_secondarymap(self.mode).insert(key, data);
+            self.touch(key);
+        }
+    }
+
+    pub fn pin_state_with_id<T: 'static>(&mut self, current_id: &Id) {
+        if let Some(key) = self.key_for(current_id) {
+            let data = self
+                .get_mut_secondarymap::<T>(self.mode)
+                .remove(key)
+                .or_else(|| {
+                    self.get_mut_secondarymap::<T>(self.mode.reverse())
+                        .remove(key)
+                });
+
+            if let Some(data) = data {
+                self.get_mut_persistent_secondarymap::<T>()
+                    .insert(key, data);
+                self.ids[key].pinned.insert(TypeId::of::<T>());
+            }
+        }
+    }
+
+    pub fn unpin_state_with_id<T: 'static>(&mut self, current_id: &Id) {
+        if let Some(key) = self.key_for(current_id) {
+            // state from `use_ref` is never swept, so only pinned state is moved
+            if !self.ids[key].pinned.remove(&TypeId::of::<T>()) {
+                return;
+            }
+
+            if let Some(data) = self.get_mut_persistent_secondarymap::<T>().remove(key) {
+                self.get_mut_secondarymap::<T>(self.mode).insert(key, data);
+                self.touch(key);
+            }
+        }
+    }
+
+    // records a hook call at `id`, returning the first one there if it differs
+    #[cfg(debug_assertions)]
+    fn record_hook_call(&mut self, id: &Id, call: HookCall) -> Option<HookCall> {
+        let key = self.key_for_or_insert(id);
+        let entry = self.ids.get_mut(key)?;
+
+        match entry.first_hook {
+            // hooks under different roots share ids without being related
+            Some(first) if first.root != call.root => None,
+            Some(first) if first.type_id != call.type_id || first.location != call.location => {
+                Some(first)
+            }
+            Some(_) => None,
+            None => {
+                entry.first_hook = Some(call);
+                None
+            }
+        }
+    }
+
+    // warns about a hook at `id` differing from the first one there, unless it was already
+    #[cfg(debug_assertions)]
+    fn warn_hook_order(&mut self, id: &Id, warning: String) {
+        let warned = match self.key_for(id).and_then(|key| self.ids.get_mut(key)) {
+            Some(entry) => std::mem::replace(&mut entry.order_warned, true),
+            None => return,
+        };
+
+        if !warned {
+            eprintln!("warning: {}", warning);
+            self.hook_order_warnings.push(warning);
+        }
+    }
+
+    // records that the state for `key` was accessed during the current cycle
+    fn touch(&mut self, key: DefaultKey) {
+        if let Some(entry) = self.ids.get_mut(key) {
+            entry.last_marked = self.cycle;
+        }
+    }
+
+    fn key_for(&self, id: &Id) -> Option<DefaultKey> {
+        self.keys_by_id.get(id).copied()
+    }
+
+    fn key_for_or_insert(&mut self, id: &Id) -> DefaultKey {
+        let ids = &mut self.ids;
+        let next_generation = &mut self.next_generation;
+        let cycle = self.cycle;
+
+        *self.keys_by_id.entry(*id).or_insert_with(|| {
+            // generations are unique across entries, so a handle from a dropped entry never
+            // matches a newer one for the same id
+            *next_generation += 1;
+            ids.insert(IdEntry {
+                id: *id,
+                generation: *next_generation,
+                generations: HashMap::new(),
+                pinned: HashSet::new(),
+                created: *next_generation,
+                last_marked: cycle,
+                #[cfg(debug_assertions)]
+                first_hook: None,
+                #[cfg(debug_assertions)]
+                order_warned: false,
+            })
+        })
+    }
+
+    fn state_exists<T: 'static>(&self, mode: Mode, id: Id) -> bool {
+        match (self.key_for(&id), self.get_secondarymap::<T>(mode)) {
+            (Some(existing_key), Some(existing_secondary_map)) => {
+                existing_secondary_map.contains_key(existing_key)
+            }
+            (_, _) => false,
+        }
+    }
+
+    fn persistent_state_exists<T: 'static>(&self, id: Id) -> bool {
+        match (self.key_for(&id), self.get_persistent_secondarymap::<T>()) {
+            (Some(existing_key), Some(existing_secondary_map)) => {
+                existing_secondary_map.contains_key(existing_key)
+            }
+            (_, _) => false,
+        }
+    }
+
+    fn get_persistent_secondarymap<T: 'static>(&self) -> Option<&SecondaryMap<DefaultKey, T>> {
+        self.data_persistent.get::<SecondaryMap<DefaultKey, T>>()
+    }
+
+    fn get_mut_persistent_secondarymap<T: 'static>(&mut self) -> &mut SecondaryMap<DefaultKey, T> {
+        self.register_type::<T>();
+        self.data_persistent
+            .entry::<SecondaryMap<DefaultKey, T>>()
+            .or_insert_with(SecondaryMap::new)
+    }
+
+    fn get_secondarymap<T: 'static>(&self, mode: Mode) -> Option<&SecondaryMap<DefaultKey, T>> {
+        self.get_datamap(mode).get::<SecondaryMap<DefaultKey, T>>()
+    }
+
+    fn get_mut_secondarymap<T: 'static>(&mut self, mode: Mode) -> &mut SecondaryMap<DefaultKey, T> {
+        if self
+            .get_datamap_mut(mode)
+            .get_mut::<SecondaryMap<DefaultKey, T>>()
+            .is_some()
+        {
+            self.get_datamap_mut(mode)
+                .get_mut::<SecondaryMap<DefaultKey, T>>()
+                .unwrap()
+        } else {
+            self.register_secondarymap::<T>(mode);
+            self.get_datamap_mut(mode)
+                .get_mut::<SecondaryMap<DefaultKey, T>>()
+                .unwrap()
+        }
+    }
+
+    fn register_secondarymap<T: 'static>(&mut self, mode: Mode) {
+        let sm: SecondaryMap<DefaultKey, T> = SecondaryMap::new();
+        self.get_datamap_mut(mode).insert(sm);
+        self.register_type::<T>();
+    }
+
+    pub fn registered_types(&self) -> Vec<&'static str> {
+        self.types.iter().map(|type_info| type_info.name).collect()
+    }
+
+    fn register_type<T: 'static>(&mut self) {
+        if !self
+            .types
+            .iter()
+            .any(|type_info| type_info.type_id == TypeId::of::<T>())
+        {
+            self.types.push(TypeInfo::of::<T>());
+        }
+    }
+
+    fn get_datamap(&self, mode: Mode) -> &anymap::Map<dyn Any> {
+        match mode {
+            Mode::A => &self.data_a,
+            Mode::B => &self.data_b,
+        }
+    }
+
+    fn get_datamap_mut(&mut self, mode: Mode) -> &mut anymap::Map<dyn Any> {
+        match mode {
+            Mode::A => &mut self.data_a,
+            Mode::B => &mut self.data_b,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn set_count(n: i32) -> State<i32> {
+        root(|| use_state(|| n))
+    }
+
+    #[test]
+    fn test_counts() {
+        let count = set_count(42);
+        assert_eq!(42, count.get(|n| *n));
+        let count = set_count(500); // noop because count already set
+        assert_eq!(42, count.get(|n| *n));
+
+        sweep();
+
+        assert_eq!(42, count.get(|n| *n));
+
+        sweep();
+        sweep();
+
+        let count = set_count(500);
         assert_eq!(500, count.get(|n| *n));
     }
+
+    #[test]
+    fn test_read_survives_panic() {
+        root(|| {
+            let store = default_store();
+            let id = Id::new();
+            set_state_with_id(&store, 7i32, id);
+
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                read_state_with_id::<i32, _, ()>(&store, id, |_| panic!("read failed"))
+            }));
+
+            assert!(result.is_err());
+            assert_eq!(7, read_state_with_id::<i32, _, i32>(&store, id, |n| *n));
+        });
+    }
+
+    #[test]
+    fn test_reentrant_reads() {
+        let count = set_count(42);
+        let (outer, inner) = count.get(|outer| (*outer, count.get(|inner| *inner)));
+        assert_eq!(42, outer);
+        assert_eq!(outer, inner);
+
+        root(|| {
+            let store = default_store();
+            let id = Id::new();
+            set_state_with_id(&store, 7i32, id);
+
+            let (outer, inner) = read_state_with_id::<i32, _, _>(&store, id, |outer| {
+                (
+                    *outer,
+                    read_state_with_id::<i32, _, _>(&store, id, |inner| *inner),
+                )
+            });
+            assert_eq!(7, outer);
+            assert_eq!(outer, inner);
+        });
+    }
+
+    #[test]
+    fn test_set_value() {
+        let count = set_count(42);
+        count.set_value(7);
+        assert_eq!(7, count.get(|n| *n));
+        assert_eq!(7, set_count(500).get(|n| *n));
+    }
+
+    #[test]
+    fn test_replace() {
+        let count = set_count(42);
+        assert_eq!(42, count.replace(7));
+        assert_eq!(7, count.get(|n| *n));
+    }
+
+    #[test]
+    fn test_take() {
+        assert_eq!(Some(42), set_count(42).take());
+        assert_eq!(500, set_count(500).get(|n| *n));
+
+        let count = set_count(7);
+        let other = count.clone();
+        assert_eq!(None, count.take());
+        assert_eq!(
+            Err(HookError::StateMissing(other.id.unwrap())),
+            other.try_get(|n| *n)
+        );
+        assert_eq!(7, set_count(7).get(|n| *n));
+    }
+
+    #[test]
+    fn test_memo() {
+        let runs = Rc::new(RefCell::new(0));
+        let memo = |deps: i32| {
+            root(|| {
+                use_memo(deps, || {
+                    *runs.borrow_mut() += 1;
+                    deps * 2
+                })
+            })
+        };
+
+        assert_eq!(4, memo(2).get(|n| *n));
+        assert_eq!(4, memo(2).get(|n| *n));
+        assert_eq!(1, *runs.borrow());
+
+        assert_eq!(6, memo(3).get(|n| *n));
+        assert_eq!(2, *runs.borrow());
+    }
+
+    #[test]
+    fn test_reducer() {
+        enum Action {
+            Increment,
+            Decrement,
+        }
+
+        let counter = || {
+            root(|| {
+                use_reducer(
+                    || 0,
+                    |count, action| match action {
+                        Action::Increment => *count += 1,
+                        Action::Decrement => *count -= 1,
+                    },
+                )
+            })
+        };
+
+        let (count, dispatch) = counter();
+        dispatch(Action::Increment);
+        dispatch(Action::Increment);
+        dispatch(Action::Decrement);
+        assert_eq!(1, count.get(|n| *n));
+
+        let (count, dispatch) = counter();
+        dispatch(Action::Increment);
+        assert_eq!(2, count.get(|n| *n));
+    }
+
+    #[test]
+    fn test_previous() {
+        let previous = |n: i32| root(|| use_previous(n));
+
+        assert_eq!(None, previous(1));
+        assert_eq!(Some(1), previous(2));
+        assert_eq!(Some(2), previous(2));
+    }
+
+    #[test]
+    fn test_ref() {
+        let set_ref = |n: i32| root(|| use_ref(|| n));
+
+        assert_eq!(42, set_ref(42).get(|n| *n));
+
+        sweep();
+        sweep();
+        sweep();
+
+        let count = set_ref(500);
+        assert_eq!(42, count.get(|n| *n));
+        assert_eq!(Some(42), count.take());
+        assert_eq!(500, set_ref(500).get(|n| *n));
+    }
+
+    #[test]
+    fn test_pin() {
+        let count = set_count(42);
+        count.pin();
+
+        sweep();
+        sweep();
+
+        assert!(state_exists_for_id::<Rc<RefCell<i32>>>(
+            &default_store(),
+            count.id.unwrap()
+        ));
+        assert_eq!(42, set_count(500).get(|n| *n));
+
+        count.unpin();
+
+        sweep();
+        sweep();
+
+        assert_eq!(500, set_count(500).get(|n| *n));
+    }
+
+    #[test]
+    fn test_unpin_ref() {
+        let reference = root(|| use_ref(|| 42));
+        reference.unpin();
+
+        sweep();
+        sweep();
+
+        assert_eq!(Ok(42), reference.try_get(|n| *n));
+
+        // pinning and unpinning leaves it out of the sweep cycle too
+        reference.pin();
+        reference.unpin();
+
+        sweep();
+        sweep();
+
+        assert_eq!(Ok(42), reference.try_get(|n| *n));
+    }
+
+    #[test]
+    fn test_unused_ids_are_dropped() {
+        let tracked_ids = || STORE.with(|store_refcell| store_refcell.borrow().keys_by_id.len());
+
+        for cycle in 0..10 {
+            for i in 0..1000 {
+                root(|| call_in_slot(&(cycle, i), || use_state(|| i)));
+            }
+            sweep();
+
+            // only this cycle's ids and the last cycle's are still held by the buffers
+            assert!(tracked_ids() <= 2000);
+            assert_eq!(
+                tracked_ids(),
+                STORE.with(|store_refcell| store_refcell.borrow().ids.len())
+            );
+        }
+
+        // keys which still hold state of another type are kept
+        let store = default_store();
+        let id = root(Id::new);
+        set_state_with_id(&store, 7i32, id);
+        set_persistent_state_with_id(&store, String::from("kept"), id);
+
+        sweep();
+        sweep();
+
+        assert_eq!(1, tracked_ids());
+        assert_eq!(
+            "kept",
+            read_state_with_id::<String, _, _>(&store, id, |s| s.clone())
+        );
+    }
+
+    #[test]
+    fn test_store_stats() {
+        set_count(42);
+        root(|| use_ref(String::new));
+
+        let stats = store_stats();
+        assert_eq!(2, stats.tracked_ids);
+        assert_eq!(1, stats.maps_a);
+        assert_eq!(0, stats.maps_b);
+        assert_eq!(1, stats.maps_persistent);
+        assert_eq!(SweepPhase::A, stats.phase);
+
+        sweep();
+        assert_eq!(SweepPhase::B, store_stats().phase);
+    }
+
+    #[test]
+    fn test_clear_all() {
+        set_count(42);
+        root(|| use_ref(String::new));
+        sweep();
+        root(|| use_state(|| 'a'));
+
+        clear_all();
+
+        let stats = store_stats();
+        assert_eq!(0, stats.tracked_ids);
+        assert_eq!(SweepPhase::A, stats.phase);
+        assert_eq!(500, set_count(500).get(|n| *n));
+    }
+
+    #[test]
+    fn test_reserve() {
+        reserve(100);
+        reserve_for::<i32>(100);
+
+        STORE.with(|store_refcell| {
+            let store = store_refcell.borrow();
+            assert!(store.ids.capacity() >= 100);
+            for mode in [Mode::A, Mode::B] {
+                let secondary_map = store.get_secondarymap::<Rc<RefCell<i32>>>(mode).unwrap();
+                assert!(secondary_map.capacity() >= 100);
+            }
+        });
+
+        assert_eq!(42, set_count(42).get(|n| *n));
+    }
+
+    #[test]
+    fn test_effect() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let effect = |deps: i32| {
+            let log = log.clone();
+            root(|| {
+                use_effect(deps, move || {
+                    log.borrow_mut().push(format!("run {}", deps));
+                    Box::new(move || {
+                        // cleanups may use hooks themselves
+                        set_count(0);
+                        log.borrow_mut().push(format!("clean {}", deps));
+                    })
+                })
+            })
+        };
+
+        effect(1);
+        effect(1);
+        effect(2);
+
+        sweep();
+        sweep();
+
+        assert_eq!(vec!["run 1", "clean 1", "run 2", "clean 2"], *log.borrow());
+    }
+
+    #[test]
+    fn test_cleanup_on_clear() {
+        let runs = Rc::new(RefCell::new(0));
+
+        let count = set_count(42);
+        let cleanup_runs = runs.clone();
+        count.on_clear(move || *cleanup_runs.borrow_mut() += 1);
+
+        sweep();
+        assert_eq!(0, *runs.borrow());
+        set_count(500); // accessed, so the state survives another sweep
+
+        sweep();
+        assert_eq!(0, *runs.borrow());
+
+        sweep();
+        assert_eq!(1, *runs.borrow());
+
+        sweep();
+        sweep();
+        assert_eq!(1, *runs.borrow());
+    }
+
+    #[test]
+    fn test_callback() {
+        let callback = |deps: i32| root(|| use_callback(deps, move |n: i32| n + deps).get_cloned());
+
+        let first = callback(1);
+        assert_eq!(3, first(2));
+        assert!(Rc::ptr_eq(&first, &callback(1)));
+
+        let second = callback(2);
+        assert_eq!(4, second(2));
+        assert!(!Rc::ptr_eq(&first, &second));
+    }
+
+    #[test]
+    fn test_set_if_changed() {
+        let count = set_count(42);
+        assert!(!count.set_if_changed(42));
+        assert!(count.set_if_changed(7));
+        assert_eq!(7, count.get(|n| *n));
+    }
+
+    #[test]
+    fn test_update_if() {
+        let count = set_count(42);
+        assert!(!count.update_if(|n| *n > 100));
+        assert!(count.update_if(|n| {
+            *n += 1;
+            true
+        }));
+        assert_eq!(43, count.get(|n| *n));
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let notifications = Rc::new(RefCell::new(0));
+        let count = set_count(42);
+
+        let counter = notifications.clone();
+        let subscription = count.subscribe(move || *counter.borrow_mut() += 1);
+
+        count.set(|n| *n += 1);
+        count.set_value(1);
+        count.replace(2);
+        assert!(!count.set_if_changed(2));
+        assert!(!count.update_if(|_| false));
+        assert_eq!(3, *notifications.borrow());
+
+        // handles from other calls at the same call site share subscribers
+        set_count(500).set_value(3);
+        assert_eq!(4, *notifications.borrow());
+
+        subscription.unsubscribe();
+        count.set_value(4);
+        assert_eq!(4, *notifications.borrow());
+    }
+
+    #[test]
+    fn test_unsubscribe_during_notification() {
+        let notifications = Rc::new(RefCell::new(0));
+        let subscription = Rc::new(RefCell::new(None::<SubscriptionHandle>));
+        let count = set_count(42);
+
+        let (counter, own_subscription) = (notifications.clone(), subscription.clone());
+        *subscription.borrow_mut() = Some(count.subscribe(move || {
+            *counter.borrow_mut() += 1;
+            if let Some(subscription) = own_subscription.borrow_mut().take() {
+                subscription.unsubscribe();
+            }
+        }));
+
+        let counter = notifications.clone();
+        let _subscription = count.subscribe(move || *counter.borrow_mut() += 10);
+
+        count.set_value(1);
+        count.set_value(2);
+        assert_eq!(21, *notifications.borrow());
+    }
+
+    #[test]
+    fn test_try_get() {
+        let count = set_count(42);
+        assert_eq!(Ok(42), count.try_get(|n| *n));
+
+        sweep();
+        sweep();
+
+        let missing = HookError::StateMissing(count.id.unwrap());
+        assert_eq!(Err(missing), count.try_get(|n| *n));
+        assert_eq!(Err(missing), count.try_set(|n| *n = 7));
+        assert_eq!(Err(missing), count.try_update(|_| true));
+        assert_eq!(Ok(1), State::controlled(1).try_get(|n| *n));
+    }
+
+    #[test]
+    #[should_panic(expected = "State does not exist.")]
+    fn test_get_missing_state() {
+        let count = set_count(42);
+
+        sweep();
+        sweep();
+
+        count.get(|n| *n);
+    }
+
+    #[test]
+    fn test_stale_handle() {
+        let count = set_count(42);
+
+        sweep();
+        sweep();
+
+        let fresh = set_count(500);
+        assert_eq!(Ok(500), fresh.try_get(|n| *n));
+        assert_eq!(
+            Err(HookError::Stale(count.id.unwrap())),
+            count.try_get(|n| *n)
+        );
+
+        let taken = set_count(7);
+        assert_eq!(None, taken.clone().take());
+        set_count(7);
+        assert_eq!(
+            Err(HookError::Stale(taken.id.unwrap())),
+            taken.try_get(|n| *n)
+        );
+    }
+
+    #[test]
+    fn test_for_each_state() {
+        root(|| {
+            for i in 0..3 {
+                call_in_slot(&i, || use_state(|| i));
+            }
+        });
+        let pinned = root(|| use_state(|| 3));
+        pinned.pin();
+        root(|| use_state(|| String::from("other type")));
+
+        let mut states = Vec::new();
+        for_each_state::<i32, _>(|id, n| states.push((id, *n)));
+        states.sort_by_key(|(_, n)| *n);
+
+        assert_eq!(
+            vec![0, 1, 2, 3],
+            states.iter().map(|(_, n)| *n).collect::<Vec<_>>()
+        );
+        assert_eq!(pinned.id.unwrap(), states[3].0);
+    }
+
+    #[test]
+    fn test_count_states() {
+        let states = || {
+            root(|| {
+                for i in 0..3 {
+                    call_in_slot(&i, || use_state(|| i));
+                }
+            })
+        };
+
+        states();
+        assert_eq!(3, count_states::<i32>());
+
+        sweep();
+        states(); // moves the states into the other buffer without duplicating them
+        assert_eq!(3, count_states::<i32>());
+        assert_eq!(0, count_states::<String>());
+
+        sweep();
+        sweep();
+        assert_eq!(0, count_states::<i32>());
+    }
+
+    #[test]
+    fn test_debug_dump() {
+        set_count(42);
+        sweep();
+        root(|| use_ref(String::new));
+
+        let dump = debug_dump();
+        assert!(dump.starts_with("tracked ids: 2\n"));
+        assert!(dump.contains(&format!(
+            "buffer A: 1 maps\n  {}: 1 entries\n",
+            std::any::type_name::<Rc<RefCell<i32>>>()
+        )));
+        assert!(dump.contains("buffer B (active): 0 maps\n"));
+        assert!(dump.contains(&format!(
+            "persistent: 1 maps\n  {}: 1 entries\n",
+            std::any::type_name::<Rc<RefCell<String>>>()
+        )));
+    }
+
+    #[test]
+    fn test_store_handle() {
+        let store = StoreHandle::new();
+        let local = root(|| store.use_state(|| 7));
+        let global = set_count(42);
+
+        // the stores are swept independently
+        store.sweep();
+        store.sweep();
+        assert!(local.try_get(|n| *n).is_err());
+        assert_eq!(Ok(42), global.try_get(|n| *n));
+        assert_eq!(1, count_states::<i32>());
+
+        let local = root(|| store.use_state(|| 7));
+        sweep();
+        sweep();
+        assert!(global.try_get(|n| *n).is_err());
+        assert_eq!(Ok(7), local.try_get(|n| *n));
+    }
+
+    #[test]
+    fn test_call_id() {
+        let (first, second) = root(|| (use_state(|| 1), use_state(|| 2)));
+        let again = root(|| use_state(|| 1));
+
+        assert_ne!(first.call_id(), second.call_id());
+        assert_eq!(first.call_id(), again.call_id());
+        assert_eq!(first, again);
+        assert_ne!(first, second);
+        assert_eq!(None, State::controlled(1).call_id());
+
+        let controlled = State::controlled(1);
+        assert_eq!(controlled, controlled.clone());
+        assert_ne!(controlled, State::controlled(1));
+
+        // handles hash by call site rather than by their data
+        #[allow(clippy::mutable_key_type)]
+        let handles: HashSet<_> = vec![first, second, again].into_iter().collect();
+        assert_eq!(2, handles.len());
+    }
+
+    #[test]
+    fn test_use_state_keyed() {
+        let counts = |keys: &[&'static str]| {
+            root(|| {
+                keys.iter()
+                    .map(|key| {
+                        use_state_keyed(*key, || 0).set(|n| {
+                            *n += 1;
+                            *n
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        assert_eq!(vec![1, 1, 1], counts(&["a", "b", "c"]));
+        assert_eq!(vec![2, 2, 1], counts(&["c", "a", "d"]));
+        assert_eq!(vec![3, 2], counts(&["a", "b"]));
+    }
+
+    #[test]
+    fn test_use_state_with_deps() {
+        let selection = |item: &'static str| root(|| use_state_with_deps(item, || 0));
+
+        selection("a").set_value(3);
+        assert_eq!(3, selection("a").get_cloned());
+        assert_eq!(0, selection("b").get_cloned());
+        assert_eq!(0, selection("a").get_cloned());
+    }
+
+    #[test]
+    fn test_use_state_default() {
+        let names = root(use_state_default::<Vec<String>>);
+        assert!(names.get(Vec::is_empty));
+
+        names.set(|names| names.push(String::from("a")));
+        assert_eq!(1, root(use_state_default::<Vec<String>>).get(Vec::len));
+    }
+
+    #[test]
+    fn test_swap() {
+        let (front, back) = root(|| (use_state(|| 1), use_state(|| 2)));
+        swap(&front, &back);
+        swap(&front, &front.clone());
+        assert_eq!(
+            (2, 1),
+            root(|| (use_state(|| 1).get_copy(), use_state(|| 2).get_copy()))
+        );
+
+        let notified = Rc::new(RefCell::new(0));
+        let counter = notified.clone();
+        let _subscription = front.subscribe(move || *counter.borrow_mut() += 1);
+        swap(&front, &State::controlled(3));
+        assert_eq!(3, front.get_copy());
+        assert_eq!(1, *notified.borrow());
+
+        // swapping counts as an access of both states
+        let other = root(|| use_state_keyed("other", || 4));
+        sweep();
+        swap(&front, &other);
+        sweep();
+        assert_eq!(4, front.get_copy());
+        assert_eq!(3, other.get_copy());
+
+        // swapping with cleared state moves the values
+        let cleared = root(|| use_state_keyed("cleared", || 5));
+        front.pin();
+        sweep();
+        sweep();
+        front.unpin();
+        sweep();
+        swap(&front, &cleared);
+        assert!(cleared.try_get(|n| *n).is_err());
+        sweep();
+        assert_eq!(5, front.get_copy());
+        assert_eq!(3, *notified.borrow());
+    }
+
+    #[test]
+    fn test_batch() {
+        let (first, second) = root(|| (use_state(|| 0), use_state(|| 0)));
+        let notified = Rc::new(RefCell::new(Vec::new()));
+
+        let _subscriptions = [(&first, "first"), (&second, "second")].map(|(state, name)| {
+            let notified = notified.clone();
+            state.subscribe(move || notified.borrow_mut().push(name))
+        });
+
+        let sum = batch(|| {
+            first.set_value(1);
+            second.set_value(2);
+            batch(|| first.set_value(3));
+            assert!(notified.borrow().is_empty());
+
+            first.get_copy() + second.get_copy()
+        });
+
+        assert_eq!(5, sum);
+        assert_eq!(vec!["first", "second"], *notified.borrow());
+
+        first.set_value(4);
+        assert_eq!(3, notified.borrow().len());
+    }
+
+    #[test]
+    fn test_marked_ids() {
+        let (first, second) = root(|| (use_state(|| 1), use_state(|| 2)));
+        let marked = marked_ids();
+        assert_eq!(2, marked.len());
+        assert!(marked.contains(&first.id.unwrap()));
+
+        sweep();
+        assert!(marked_ids().is_empty());
+
+        root(|| use_state(|| 1));
+        assert_eq!(vec![first.id.unwrap()], marked_ids());
+        assert_ne!(first.id, second.id);
+    }
+
+    #[test]
+    fn test_state_marked() {
+        let store = default_store();
+        let marked = |state: &State<i32>| {
+            state_marked_with_id::<Rc<RefCell<i32>>>(&store, state.id.unwrap())
+        };
+
+        // fresh state
+        let count = set_count(1);
+        assert!(marked(&count));
+
+        // state not read for one cycle
+        sweep();
+        assert!(!marked(&count));
+        assert!(count.try_get(|n| *n).is_ok());
+
+        // state read this cycle
+        read_state_with_id::<Rc<RefCell<i32>>, _, _>(&store, count.id.unwrap(), |_| ());
+        assert!(marked(&count));
+        sweep();
+        assert_eq!(Ok(1), count.try_get(|n| *n));
+
+        sweep();
+        assert!(!marked(&count));
+        assert!(count.try_get(|n| *n).is_err());
+
+        // state exempt from sweeping is never moved into the buffers
+        let kept = root(|| use_ref(|| 2));
+        mark_state_with_id::<Rc<RefCell<i32>>>(&store, kept.id.unwrap());
+        assert!(!marked(&kept));
+        sweep();
+        sweep();
+        assert_eq!(Ok(2), kept.try_get(|n| *n));
+    }
+
+    #[test]
+    fn test_use_state_init_once() {
+        let runs = Rc::new(RefCell::new(0));
+        let resource = || {
+            root(|| {
+                use_state_init_once(|| {
+                    *runs.borrow_mut() += 1;
+                    "resource"
+                })
+            })
+        };
+
+        resource();
+        for _ in 0..3 {
+            sweep();
+        }
+        assert_eq!("resource", resource().get_copy());
+        assert_eq!(1, *runs.borrow());
+    }
+
+    #[test]
+    fn test_use_computed() {
+        let (width, height) = root(|| (use_state(|| 2), use_state(|| 3)));
+        let area = || {
+            root(|| {
+                call_in_slot("area", || {
+                    use_computed(|| width.get_copy() * height.get_copy())
+                })
+            })
+        };
+
+        let notified = Rc::new(RefCell::new(0));
+        let counter = notified.clone();
+        let _subscription = area().subscribe(move || *counter.borrow_mut() += 1);
+
+        assert_eq!(6, area().get_copy());
+        assert_eq!(0, *notified.borrow());
+
+        width.set_value(3);
+        height.set_value(2);
+        assert_eq!(6, area().get_copy());
+        assert_eq!(0, *notified.borrow());
+
+        height.set_value(4);
+        assert_eq!(12, area().get_copy());
+        assert_eq!(1, *notified.borrow());
+    }
+
+    #[test]
+    fn test_mark_all() {
+        let (first, second) = root(|| (use_state(|| 1), use_state(|| 2)));
+        let other = root(|| call_in_slot("other", || use_state(|| 3)));
+
+        sweep();
+        mark_all::<i32>(&[first.id.unwrap(), second.id.unwrap()]);
+        sweep();
+
+        assert_eq!(Ok(1), first.try_get(|n| *n));
+        assert_eq!(Ok(2), second.try_get(|n| *n));
+        assert!(other.try_get(|n| *n).is_err());
+    }
+
+    #[test]
+    fn test_sweep_with_report() {
+        root(|| (use_state(|| 1), use_state(|| 2), use_state(String::new)));
+        root(|| call_in_slot("kept", || use_ref(|| 3)));
+
+        assert_eq!(SweepReport::default(), sweep_with_report());
+        assert_eq!(
+            SweepReport {
+                ids_cleared: 3,
+                maps_cleared: 2,
+            },
+            sweep_with_report()
+        );
+
+        // state accessed on every cycle leaves its map empty, which doesn't count
+        for _ in 0..3 {
+            root(|| use_state(|| 1));
+            assert_eq!(SweepReport::default(), sweep_with_report());
+        }
+    }
+
+    #[test]
+    fn test_sweep_policy() {
+        // keeps unaccessed state for one extra sweep
+        #[derive(Default)]
+        struct Lenient {
+            offered: HashSet<Id>,
+        }
+
+        impl SweepPolicy for Lenient {
+            fn should_clear(&mut self, id: Id) -> bool {
+                !self.offered.insert(id)
+            }
+        }
+
+        let store = StoreHandle::with_policy(Lenient::default());
+        let cleared = Rc::new(RefCell::new(false));
+        let state = root(|| store.use_state(|| 1));
+        let flag = cleared.clone();
+        state.on_clear(move || *flag.borrow_mut() = true);
+
+        store.sweep();
+        store.sweep();
+        assert_eq!(Ok(1), state.try_get(|n| *n));
+        assert!(!*cleared.borrow());
+
+        store.sweep();
+        assert!(state.try_get(|n| *n).is_err());
+        assert!(*cleared.borrow());
+    }
+
+    #[test]
+    fn test_sweep_older_than() {
+        let (first, second) = root(|| (use_state(|| 1), use_state(|| 2)));
+        sweep();
+        let fresh = root(|| call_in_slot("fresh", || use_state(|| 3)));
+
+        assert_eq!(0, sweep_older_than(1).ids_cleared);
+        assert_eq!(2, sweep_older_than(0).ids_cleared);
+        assert!(first.try_get(|n| *n).is_err());
+        assert!(second.try_get(|n| *n).is_err());
+        assert_eq!(Ok(3), fresh.try_get(|n| *n));
+    }
+
+    #[test]
+    fn test_use_state_tuple() {
+        let (count, set_count) = root(|| use_state_tuple(|| 1));
+        set_count(2);
+        assert_eq!(2, count.get_copy());
+        assert_eq!(2, root(|| use_state_tuple(|| 1)).0.get_copy());
+    }
+
+    #[test]
+    #[should_panic(expected = "State is already in use")]
+    fn test_nested_write() {
+        let count = set_count(1);
+        count.set(|n| *n += count.get_copy());
+    }
+
+    #[test]
+    fn test_use_id() {
+        let ids = || root(|| (use_id(), use_id()));
+        let (first, second) = ids();
+
+        assert_ne!(first, second);
+        assert_eq!(2, store_stats().tracked_ids);
+        assert_eq!((first, second), ids());
+
+        sweep();
+        assert_eq!(0, store_stats().tracked_ids);
+        assert_eq!((first, second), ids());
+    }
+
+    #[test]
+    fn test_current_phase() {
+        assert_eq!(SweepPhase::A, current_phase());
+        sweep();
+        assert_eq!(SweepPhase::B, current_phase());
+        assert_eq!(store_stats().phase, current_phase());
+        sweep();
+        assert_eq!(SweepPhase::A, current_phase());
+    }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        reserve_for::<i32>(1000);
+        root(|| {
+            for i in 0..1000 {
+                call_in_slot(&i, || use_state(|| i));
+            }
+        });
+        let kept = root(|| use_ref(|| 0));
+
+        sweep();
+        sweep();
+        shrink_to_fit();
+
+        let stats = store_stats();
+        assert_eq!(0, stats.maps_a + stats.maps_b);
+        assert_eq!(1, stats.maps_persistent);
+        assert_eq!(Ok(0), kept.try_get(|n| *n));
+        STORE.with(|store_refcell| assert!(store_refcell.borrow().keys_by_id.capacity() < 1000));
+
+        // storage is recreated as needed
+        assert_eq!(5, set_count(5).get_copy());
+    }
+
+    #[test]
+    fn test_reducer_with_middleware() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let (count, dispatch) = root(|| {
+            use_reducer_with_middleware(
+                || 0,
+                |count: &mut i32, delta: i32| *count += delta,
+                |count, delta| log.borrow_mut().push((*count, *delta)),
+            )
+        });
+
+        dispatch(2);
+        dispatch(-1);
+        assert_eq!(1, count.get_copy());
+        assert_eq!(vec![(0, 2), (2, -1)], *log.borrow());
+    }
+
+    #[test]
+    fn test_state_entry() {
+        let entry = || root(state_entry::<i32>);
+
+        let inserted = match entry() {
+            StateEntry::Occupied(_) => panic!("state should not exist yet"),
+            StateEntry::Vacant(vacant) => vacant.insert(5),
+        };
+
+        match entry() {
+            StateEntry::Occupied(state) => assert_eq!(inserted, state),
+            StateEntry::Vacant(_) => panic!("state should exist"),
+        }
+
+        sweep();
+        sweep();
+        assert!(matches!(entry(), StateEntry::Vacant(_)));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Hooks must be called unconditionally")]
+    fn test_hook_type_mismatch() {
+        let conditional = |flag: bool| {
+            root(|| {
+                if flag {
+                    use_state(|| 0);
+                } else {
+                    use_state(String::new);
+                }
+            })
+        };
+
+        enable_hook_type_checks(true);
+        conditional(true);
+        conditional(false);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_hook_type_checks_across_roots() {
+        fn set_name(name: &str) -> State<String> {
+            root(|| use_state(|| name.to_string()))
+        }
+
+        enable_hook_type_checks(true);
+        enable_hook_order_checks(true);
+        set_count(1);
+        set_name("name");
+        set_count(2);
+
+        assert_eq!(1, set_count(3).get_copy());
+        assert_eq!("name", set_name("other").get_cloned());
+        assert!(take_hook_order_warnings().is_empty());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_hook_order_checks() {
+        enable_hook_order_checks(true);
+        let conditional = |flag: bool| {
+            root(|| {
+                if flag {
+                    use_state(|| 0);
+                } else {
+                    use_state(|| 1);
+                }
+            })
+        };
+
+        conditional(true);
+        conditional(true);
+        assert!(take_hook_order_warnings().is_empty());
+
+        // each call site is only warned about once
+        conditional(false);
+        conditional(false);
+        conditional(true);
+        let warnings = take_hook_order_warnings();
+        assert_eq!(1, warnings.len());
+        assert!(warnings[0].contains("but it was first called from"));
+        assert!(take_hook_order_warnings().is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_to_stream() {
+        use futures_core::Stream;
+        use std::pin::Pin;
+        use std::sync::Arc;
+        use std::task::{Context, Poll, Wake};
+
+        struct Noop;
+
+        impl Wake for Noop {
+            fn wake(self: Arc<Self>) {}
+        }
+
+        let waker = Arc::new(Noop).into();
+        let mut cx = Context::from_waker(&waker);
+
+        let count = set_count(0);
+        let mut stream = count.clone().to_stream();
+        let mut poll = |stream: &mut StateStream<i32>| Pin::new(stream).poll_next(&mut cx);
+        assert_eq!(Poll::Pending, poll(&mut stream));
+
+        // intermediate writes are dropped
+        count.set_value(1);
+        count.set_value(2);
+        assert_eq!(Poll::Ready(Some(2)), poll(&mut stream));
+        assert_eq!(Poll::Pending, poll(&mut stream));
+
+        sweep();
+        sweep();
+        assert_eq!(Poll::Ready(None), poll(&mut stream));
+    }
+
+    #[test]
+    fn test_raw_key() {
+        let (first, second) = root(|| (use_state(|| 1), use_state(|| 2)));
+        let key = first.raw_key();
+
+        assert!(key.is_some());
+        assert_ne!(key, second.raw_key());
+        assert_eq!(key, set_count(1).raw_key());
+        assert_eq!(None, State::controlled(1).raw_key());
+
+        sweep();
+        sweep();
+        assert_eq!(None, first.raw_key());
+    }
+
+    #[test]
+    fn test_meta() {
+        #[derive(Clone, Debug, PartialEq)]
+        struct Dirty(bool);
+
+        let count = set_count(1);
+        count.set_meta("count");
+        count.set_meta(Dirty(true));
+        count.set_meta(Dirty(false));
+
+        assert_eq!(Some("count"), set_count(1).get_meta::<&str>());
+        assert_eq!(Some(Dirty(false)), count.get_meta::<Dirty>());
+        assert_eq!(None, count.get_meta::<i32>());
+
+        State::controlled(1).set_meta("controlled");
+        assert_eq!(None, State::controlled(1).get_meta::<&str>());
+
+        sweep();
+        sweep();
+        assert_eq!(None, set_count(1).get_meta::<&str>());
+    }
+
+    #[test]
+    fn test_label() {
+        let (count, name) = root(|| (use_state(|| 1), use_state(String::new)));
+        count.label("count");
+        name.label("name");
+
+        let mut labels = Vec::new();
+        for_each_state::<i32, _>(|id, _| labels.extend(label_for(id)));
+        assert_eq!(vec!["count"], labels);
+
+        sweep();
+        sweep();
+        let dump = debug_dump();
+        assert!(dump.ends_with("labels: 2\n  count: no state\n  name: no state\n"));
+
+        // labels outlive the state
+        set_count(1);
+        assert!(debug_dump().contains(&format!(
+            "  count: {}\n",
+            std::any::type_name::<Rc<RefCell<i32>>>()
+        )));
+    }
+
+    #[test]
+    fn test_fork_store() {
+        register_clone::<i32>();
+        let count = set_count(1);
+        root(|| call_in_slot("name", || use_state(|| String::from("a"))));
+
+        let fork = fork_store();
+        count.set_value(2);
+
+        let forked = root(|| fork.use_state(|| 0));
+        assert_eq!(1, forked.get_copy());
+        assert_eq!(2, count.get_copy());
+
+        // unregistered types aren't copied
+        let name = root(|| call_in_slot("name", || fork.use_state(String::new)));
+        assert_eq!("", name.get_cloned());
+
+        // the stores are swept independently
+        fork.sweep();
+        fork.sweep();
+        assert_eq!(Ok(2), count.try_get(|n| *n));
+    }
+
+    #[test]
+    fn test_snapshot_order() {
+        let keys = [5, 3, 9, 1, 7];
+        let states = || {
+            root(|| {
+                for key in keys {
+                    use_state_keyed(key, || key);
+                }
+            })
+        };
+
+        states();
+        let values: Vec<_> = snapshot::<i32>().into_iter().map(|(_, n)| n).collect();
+        assert_eq!(keys.to_vec(), values);
+
+        sweep();
+        states();
+        assert_eq!(
+            values,
+            snapshot::<i32>()
+                .into_iter()
+                .map(|(_, n)| n)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_mount_unmount() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let component = || {
+            use_mount(|| log.borrow_mut().push("mount"));
+            let unmount_log = log.clone();
+            use_unmount(move || unmount_log.borrow_mut().push("unmount"));
+        };
+
+        test_cycles(2, component);
+        assert_eq!(vec!["mount"], *log.borrow());
+
+        sweep();
+        assert_eq!(vec!["mount", "unmount"], *log.borrow());
+
+        test_cycle(component);
+        assert_eq!(vec!["mount", "unmount", "mount"], *log.borrow());
+    }
+
+    #[test]
+    fn test_shared_state() {
+        let frame = || {
+            root(|| {
+                let theme = call_in_slot("a", || use_shared_state("theme", || "light"));
+                let other = call_in_slot("b", || {
+                    call_in_slot(&1, || use_shared_state("theme", || "dark"))
+                });
+                (theme, other)
+            })
+        };
+        let (theme, other) = frame();
+        assert_eq!(theme, other);
+        assert_eq!((theme.clone(), other.clone()), frame());
+
+        // roots elsewhere and calls outside of any root have their own state
+        assert_ne!(theme, root(|| use_shared_state("theme", || "light")));
+        assert_ne!(theme, use_shared_state("theme", || "light"));
+        assert_eq!(
+            use_shared_state("theme", || 1),
+            use_shared_state("theme", || 2)
+        );
+
+        other.set_value("dark");
+        assert_eq!("dark", theme.get(|theme| *theme));
+        assert_ne!(theme, root(|| use_shared_state("font", || "light")));
+
+        // neither other hooks nor other types of state share the key
+        enable_hook_type_checks(true);
+        assert_ne!(theme, root(|| use_state_keyed("theme", || "light")));
+        assert_eq!(0, root(|| use_shared_state("theme", || 0)).get_copy());
+    }
+
+    #[test]
+    fn test_cycles_result() {
+        let mut n = 0;
+        let last = test_cycles(3, || {
+            n += 1;
+            use_previous(n)
+        });
+        assert_eq!(Some(2), last);
+
+        // the state is cleared by the sweep after the first cycle it's not used in
+        let count = test_cycle(|| use_state_keyed("count", || 1));
+        assert_eq!(Ok(1), count.try_get(|n| *n));
+        sweep();
+        assert!(count.try_get(|n| *n).is_err());
+    }
+
+    #[test]
+    fn test_take_after_sweep() {
+        let count = set_count(42);
+        sweep();
+        assert_eq!(Some(42), count.take());
+        assert_eq!(0, count_states::<i32>());
+    }
+
+    #[test]
+    fn test_set_counts_as_access() {
+        let count = set_count(1);
+        sweep();
+
+        count.set_value(2);
+        sweep();
+        assert_eq!(Ok(2), count.try_get(|n| *n));
+
+        sweep();
+        assert!(count.try_get(|n| *n).is_err());
+    }
+
+    #[test]
+    fn test_state_when() {
+        let cycle = |cond: bool| {
+            root(|| {
+                let state = use_state_when(cond, || 1);
+                (state, use_state(|| 2))
+            })
+        };
+
+        let (state, other) = cycle(true);
+        state.unwrap().set_value(3);
+        assert_eq!(other, cycle(false).1);
+
+        sweep();
+        assert_eq!(3, cycle(true).0.unwrap().get(|n| *n));
+
+        cycle(false);
+        sweep();
+        sweep();
+        assert_eq!(1, cycle(true).0.unwrap().get(|n| *n));
+    }
+
+    #[test]
+    fn test_zip_get() {
+        let (count, name) = root(|| (use_state(|| 2), use_state(|| String::from("ab"))));
+        assert_eq!(
+            "abab",
+            zip_get(&count, &name, |count, name| name.repeat(*count))
+        );
+        assert_eq!(4, zip_get(&count, &count, |a, b| a + b));
+    }
+
+    #[test]
+    fn test_weak_state() {
+        let count = set_count(1);
+        let weak = count.downgrade();
+        drop(count);
+
+        assert_eq!(Some(1), weak.upgrade().map(|count| count.get(|n| *n)));
+
+        sweep();
+        sweep();
+        assert!(weak.upgrade().is_none());
+
+        let controlled = State::controlled(2);
+        let weak = controlled.downgrade();
+        assert_eq!(Some(controlled.clone()), weak.upgrade());
+        drop(controlled);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn test_derive_hooks() {
+        #[derive(Hooks)]
+        struct Form {
+            name: State<String>,
+            age: State<u32>,
+        }
+
+        let form = || root(Form::use_state);
+
+        let first = form();
+        first.age.set_value(30);
+        first.name.set_value(String::from("a"));
+
+        let second = form();
+        assert_eq!(first.name, second.name);
+        assert_eq!(30, second.age.get(|age| *age));
+        assert_eq!("a", second.name.get(|name| name.clone()));
+    }
+
+    #[test]
+    fn test_without_sweep() {
+        let count = set_count(1);
+
+        let report = without_sweep(|| {
+            sweep();
+            without_sweep(sweep);
+            sweep_with_report()
+        });
+        assert_eq!(SweepReport::default(), report);
+        assert_eq!(Ok(1), count.try_get(|n| *n));
+
+        sweep();
+        sweep();
+        assert!(count.try_get(|n| *n).is_err());
+    }
+
+    #[test]
+    fn test_registered_types() {
+        assert!(registered_types().is_empty());
+
+        root(|| {
+            use_state(|| 0_u8);
+            use_state(String::new);
+            use_state(|| 1_u8);
+        });
+        assert_eq!(
+            vec![
+                std::any::type_name::<Rc<RefCell<u8>>>(),
+                std::any::type_name::<Rc<RefCell<String>>>()
+            ],
+            registered_types()
+        );
+    }
+
+    #[test]
+    fn test_toggle() {
+        let (open, toggle) = root(|| use_toggle(false));
+        toggle();
+        assert!(open.get(|open| *open));
+
+        toggle();
+        assert!(!root(|| use_toggle(true)).0.get(|open| *open));
+    }
+
+    #[test]
+    fn test_counter() {
+        let counter = root(|| use_counter(10));
+        counter.increment();
+        counter.add(5);
+        counter.decrement();
+        assert_eq!(15, counter.value());
+
+        let counter = root(|| use_counter(0));
+        assert_eq!(15, counter.value());
+        counter.reset();
+        assert_eq!(10, counter.value());
+    }
+
+    #[test]
+    fn test_counter_of() {
+        let (items, ratio) = root(|| (use_counter_of(0_usize), use_counter_of(1.5)));
+        items.increment();
+        items.add(2);
+        items.decrement();
+        assert_eq!(2, items.value());
+
+        ratio.add(0.25);
+        ratio.increment();
+        ratio.sub(0.5);
+        assert_eq!(2.25, ratio.value());
+        ratio.reset();
+        assert_eq!(1.5, ratio.value());
+    }
+
+    #[test]
+    fn test_stale_handle_has_no_effect() {
+        let old = set_count(1);
+        sweep();
+        sweep();
+
+        let new = set_count(2);
+        old.pin();
+        old.set_meta("old");
+        old.on_clear(|| panic!("cleanup registered through a stale handle"));
+        assert_eq!(None, old.take());
+
+        assert_eq!(Ok(2), new.try_get(|n| *n));
+        assert_eq!(None, new.get_meta::<&str>());
+
+        // the state wasn't pinned, so it's cleared as usual
+        sweep();
+        sweep();
+        assert!(new.try_get(|n| *n).is_err());
+    }
+
+    #[test]
+    fn test_subscribers_cleared_with_state() {
+        let notified = Rc::new(RefCell::new(0));
+        let counter = notified.clone();
+        let old = set_count(1);
+        let _subscription = old.subscribe(move || *counter.borrow_mut() += 1);
+
+        sweep();
+        sweep();
+        set_count(2).set_value(3);
+        let _stale = old.subscribe(|| panic!("subscribed through a stale handle"));
+        set_count(2).set_value(4);
+        assert_eq!(0, *notified.borrow());
+    }
+
+    #[test]
+    fn test_meta_per_state_type() {
+        // both are the first state of the root, so they share a call site
+        let number = root(|| use_state(|| 0));
+        let text = root(|| use_state(|| "text"));
+        number.set_meta("number");
+        assert_eq!(None, text.get_meta::<&str>());
+
+        text.set_meta("text");
+        number.take();
+        assert_eq!(Some("text"), text.get_meta::<&str>());
+    }
+
+    #[test]
+    fn test_generation_per_state_type() {
+        // both are the first state of the root, so they share a call site
+        let number = root(|| use_state(|| 1));
+        let text = root(|| use_state(|| "text"));
+        number.take();
+        assert_eq!(Ok("text"), text.try_get(|text| *text));
+
+        let number = root(|| use_state(|| 2));
+        text.pin();
+        sweep();
+        sweep();
+        assert!(number.try_get(|n| *n).is_err());
+        assert_eq!("text", text.get(|text| *text));
+    }
+
+    #[test]
+    fn test_subscribers_per_state_type() {
+        // both are the first state of the root, so they share a call site
+        let number = root(|| use_state(|| 1));
+        let text = root(|| use_state(|| "text"));
+
+        let notified = Rc::new(RefCell::new(0));
+        let counter = notified.clone();
+        let _subscription = text.subscribe(move || *counter.borrow_mut() += 1);
+
+        number.set_value(2);
+        number.take();
+        assert_eq!(0, *notified.borrow());
+
+        text.set_value("other");
+        assert_eq!(1, *notified.borrow());
+    }
+
+    #[test]
+    fn test_take_drops_cleanups() {
+        let runs = Rc::new(RefCell::new(0));
+
+        let count = set_count(1);
+        let cleanup_runs = runs.clone();
+        count.on_clear(move || *cleanup_runs.borrow_mut() += 1);
+        count.take();
+
+        set_count(2);
+        sweep();
+        sweep();
+        assert_eq!(0, *runs.borrow());
+    }
 }